@@ -0,0 +1,127 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{Days, Local, NaiveDate};
+use std::time::{Duration, Instant};
+
+const MAX_EXPRESSION_CHARS: usize = 500;
+const MAX_RESULT_CHARS: usize = 2000;
+// e.g. 10^10^10 would run forever
+const TIMEOUT: Duration = Duration::from_secs(1);
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Calculation {
+    /// the expression actually evaluated, relative dates resolved
+    pub(crate) expression: String,
+    pub(crate) result: String,
+}
+
+struct Deadline(Instant);
+
+impl fend_core::Interrupt for Deadline {
+    fn should_interrupt(&self) -> bool {
+        Instant::now() > self.0
+    }
+}
+
+/// Evaluate the expression locally with fend, e.g. "5 miles to km" or "@2024-03-01 + 30 days".
+/// Numbers are arbitrary precision, and nothing but the calculation can run.
+pub(crate) fn calculate(expression: &str) -> Result<Calculation> {
+    let expression = expression.trim();
+    if expression.is_empty() {
+        bail!("nothing to calculate");
+    }
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        bail!("the expression is too long");
+    }
+    let expression = resolve_dates(expression, Local::now().date_naive());
+
+    if let Some(days) = days_between(&expression) {
+        let unit = if days.abs() == 1 { "day" } else { "days" };
+        return Ok(Calculation {
+            result: format!("{} {}", days, unit),
+            expression,
+        });
+    }
+
+    let mut context = fend_core::Context::new();
+    let deadline = Deadline(Instant::now() + TIMEOUT);
+    let ret =
+        fend_core::evaluate_with_interrupt(&expression, &mut context, &deadline).map_err(|e| {
+            match e.as_str() {
+                "interrupted" => anyhow!("the calculation took too long"),
+                _ => anyhow!(e),
+            }
+        })?;
+    let result = ret.get_main_result().trim();
+    if result.is_empty() {
+        bail!("the expression has no result");
+    }
+    if result.chars().count() > MAX_RESULT_CHARS {
+        bail!("the result is too long to tell");
+    }
+    Ok(Calculation {
+        result: result.to_string(),
+        expression,
+    })
+}
+
+/// fend doesn't know what day it is, so replace today, tomorrow and yesterday with the dates.
+fn resolve_dates(expression: &str, today: NaiveDate) -> String {
+    let dates = [
+        ("today", Some(today)),
+        ("tomorrow", today.checked_add_days(Days::new(1))),
+        ("yesterday", today.checked_sub_days(Days::new(1))),
+    ];
+    expression
+        .split(' ')
+        .map(|word| {
+            let date = dates
+                .iter()
+                .find(|(name, _)| word.eq_ignore_ascii_case(name))
+                .and_then(|(_, date)| *date);
+            match date {
+                Some(date) => format!("@{}", date.format(DATE_FORMAT)),
+                None => word.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Days from the second date to the first, for "@2024-03-01 - @2024-01-01" which fend can't do.
+fn days_between(expression: &str) -> Option<i64> {
+    let (a, b) = expression.split_once(" - ")?;
+    let parse = |v: &str| NaiveDate::parse_from_str(v.trim().trim_start_matches('@'), DATE_FORMAT);
+    let (a, b) = (parse(a).ok()?, parse(b).ok()?);
+    Some((a - b).num_days())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(expression: &str) -> String {
+        calculate(expression).unwrap().result
+    }
+
+    #[test]
+    fn test_calculate_should_be_exact() {
+        assert_eq!(result("0.1 + 0.2"), "0.3");
+        assert_eq!(result("2^100"), "1267650600228229401496703205376");
+        assert_eq!(result("5 miles to km"), "8.04672 km");
+        assert_eq!(result("3 hours + 20 min to min"), "200 mins");
+        assert_eq!(result("@2024-03-01 + 30 days"), "Sunday, 31 March 2024");
+        assert_eq!(result("@2024-03-01 - @2024-01-01"), "60 days");
+
+        let today = NaiveDate::from_ymd_opt(2024, 2, 28).unwrap();
+        assert_eq!(
+            resolve_dates("Tomorrow + 1 day", today),
+            "@2024-02-29 + 1 day"
+        );
+
+        assert!(calculate("1/0").is_err());
+        assert!(calculate("rm -rf /").is_err());
+        let err = calculate("10^10^10").unwrap_err();
+        assert_eq!(err.to_string(), "the calculation took too long");
+    }
+}
@@ -0,0 +1,260 @@
+use crate::tools::{ChartKind, DrawChartArgs};
+use anyhow::{bail, Result};
+use plotters::{
+    coord::{types::RangedCoordf64, Shift},
+    prelude::*,
+};
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 480;
+const MAX_SERIES: usize = 10;
+const MAX_POINTS: usize = 200;
+const FONT: &str = "sans-serif";
+
+// tableau 10, distinguishable on a white background
+const PALETTE: [RGBColor; 10] = [
+    RGBColor(78, 121, 167),
+    RGBColor(242, 142, 43),
+    RGBColor(225, 87, 89),
+    RGBColor(118, 183, 178),
+    RGBColor(89, 161, 79),
+    RGBColor(237, 201, 72),
+    RGBColor(176, 122, 161),
+    RGBColor(255, 157, 167),
+    RGBColor(156, 117, 95),
+    RGBColor(186, 176, 172),
+];
+
+type Area<'a> = DrawingArea<SVGBackend<'a>, Shift>;
+type Chart<'a, 'b> = ChartContext<'a, SVGBackend<'b>, Cartesian2d<RangedCoordf64, RangedCoordf64>>;
+
+/// Render the chart described by the tool arguments into an svg document.
+pub(crate) fn render_chart(args: &DrawChartArgs) -> Result<String> {
+    validate(args)?;
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE)?;
+        match args.kind {
+            ChartKind::Line => draw_line(&root, args)?,
+            ChartKind::Bar => draw_bar(&root, args)?,
+            ChartKind::Pie => draw_pie(&root, args)?,
+        }
+        root.present()?;
+    }
+    Ok(svg)
+}
+
+// the data comes from the model, so keep it to something we can draw
+fn validate(args: &DrawChartArgs) -> Result<()> {
+    if args.series.is_empty() || args.series.iter().all(|s| s.values.is_empty()) {
+        bail!("no data to draw the chart");
+    }
+    if args.series.len() > MAX_SERIES {
+        bail!("too many series, at most {} are supported", MAX_SERIES);
+    }
+    if args.series.iter().any(|s| s.values.len() > MAX_POINTS) {
+        bail!("too many data points, at most {} are supported", MAX_POINTS);
+    }
+    if args
+        .series
+        .iter()
+        .flat_map(|s| &s.values)
+        .any(|v| !v.is_finite())
+    {
+        bail!("the chart data contains invalid numbers");
+    }
+    Ok(())
+}
+
+fn draw_line(root: &Area, args: &DrawChartArgs) -> Result<()> {
+    let labels = labels(args);
+    let mut chart = build_chart(root, args, labels.len())?;
+    draw_mesh(&mut chart, args, &labels)?;
+    for (i, series) in args.series.iter().enumerate() {
+        let color = PALETTE[i % PALETTE.len()];
+        let points = series
+            .values
+            .iter()
+            .enumerate()
+            .map(|(x, y)| (x as f64, *y));
+        chart
+            .draw_series(LineSeries::new(points, color.stroke_width(3)).point_size(4))?
+            .label(&series.name)
+            .legend(move |(x, y)| {
+                PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(3))
+            });
+    }
+    draw_legend(&mut chart, args)
+}
+
+fn draw_bar(root: &Area, args: &DrawChartArgs) -> Result<()> {
+    let labels = labels(args);
+    let mut chart = build_chart(root, args, labels.len())?;
+    draw_mesh(&mut chart, args, &labels)?;
+    // bars of the same label are grouped side by side
+    let width = 0.8 / args.series.len() as f64;
+    for (i, series) in args.series.iter().enumerate() {
+        let color = PALETTE[i % PALETTE.len()];
+        let offset = -0.4 + width * i as f64;
+        let bars = series.values.iter().enumerate().map(|(x, y)| {
+            let x0 = x as f64 + offset;
+            Rectangle::new([(x0, 0.0), (x0 + width, *y)], color.filled())
+        });
+        chart
+            .draw_series(bars)?
+            .label(&series.name)
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 10, y + 5)], color.filled()));
+    }
+    draw_legend(&mut chart, args)
+}
+
+fn draw_pie(root: &Area, args: &DrawChartArgs) -> Result<()> {
+    let values = &args.series[0].values;
+    if values.iter().any(|v| *v < 0.0) || values.iter().sum::<f64>() <= 0.0 {
+        bail!("a pie chart needs positive values");
+    }
+    let labels = labels(args);
+    let labels = &labels[..values.len()];
+    let colors: Vec<_> = (0..values.len())
+        .map(|i| PALETTE[i % PALETTE.len()])
+        .collect();
+
+    let area = root.titled(&args.title, (FONT, 28))?;
+    let (w, h) = area.dim_in_pixel();
+    let center = (w as i32 / 2, h as i32 / 2);
+    let radius = w.min(h) as f64 * 0.38;
+    let mut pie = Pie::new(&center, &radius, values, &colors, labels);
+    pie.start_angle(-90.0);
+    pie.label_style((FONT, 16).into_font().color(&BLACK));
+    pie.percentages((FONT, 14).into_font().color(&WHITE));
+    area.draw(&pie)?;
+    Ok(())
+}
+
+fn build_chart<'a, 'b>(
+    root: &'a Area<'b>,
+    args: &DrawChartArgs,
+    len: usize,
+) -> Result<Chart<'a, 'b>> {
+    let (min, max) = value_range(args);
+    let chart = ChartBuilder::on(root)
+        .caption(&args.title, (FONT, 28))
+        .margin(20)
+        .x_label_area_size(if args.x_label.is_some() { 50 } else { 30 })
+        .y_label_area_size(if args.y_label.is_some() { 70 } else { 50 })
+        .build_cartesian_2d(-0.5..len as f64 - 0.5, min..max)?;
+    Ok(chart)
+}
+
+fn draw_mesh(chart: &mut Chart, args: &DrawChartArgs, labels: &[String]) -> Result<()> {
+    // x is the index of the label, only whole numbers get one
+    let formatter = |x: &f64| {
+        let i = x.round();
+        if (x - i).abs() < 1e-6 && i >= 0.0 && (i as usize) < labels.len() {
+            labels[i as usize].clone()
+        } else {
+            String::new()
+        }
+    };
+    let mut mesh = chart.configure_mesh();
+    mesh.disable_x_mesh()
+        .x_labels(labels.len())
+        .x_label_formatter(&formatter)
+        .y_label_formatter(&|y| format_value(*y));
+    if let Some(v) = &args.x_label {
+        mesh.x_desc(v);
+    }
+    if let Some(v) = &args.y_label {
+        mesh.y_desc(v);
+    }
+    mesh.draw()?;
+    Ok(())
+}
+
+fn draw_legend<'a, 'b: 'a>(chart: &mut Chart<'a, 'b>, args: &DrawChartArgs) -> Result<()> {
+    if args.series.len() > 1 {
+        chart
+            .configure_series_labels()
+            .position(SeriesLabelPosition::UpperRight)
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+    }
+    Ok(())
+}
+
+// axis values without the float noise, e.g. "0.3" instead of "0.30000000000000004"
+fn format_value(v: f64) -> String {
+    let s = format!("{:.4}", v);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Labels for every data point, numbered from 1 when the model gives too few.
+fn labels(args: &DrawChartArgs) -> Vec<String> {
+    let len = args
+        .series
+        .iter()
+        .map(|s| s.values.len())
+        .max()
+        .unwrap_or_default();
+    (0..len)
+        .map(|i| {
+            args.labels
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| (i + 1).to_string())
+        })
+        .collect()
+}
+
+/// The y range covering all values and zero, with some room above the highest one.
+fn value_range(args: &DrawChartArgs) -> (f64, f64) {
+    let values = args.series.iter().flat_map(|s| &s.values);
+    let (min, max) = values.fold((0f64, 0f64), |(min, max), v| (min.min(*v), max.max(*v)));
+    let span = if max > min { max - min } else { 1.0 };
+    let min = if min < 0.0 { min - span * 0.1 } else { min };
+    (min, max + span * 0.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ChartSeries;
+
+    fn args(kind: ChartKind, values: Vec<f64>) -> DrawChartArgs {
+        DrawChartArgs {
+            title: "Steps <per> week".to_string(),
+            kind,
+            labels: vec!["Mon".to_string(), "Tue".to_string()],
+            series: vec![
+                ChartSeries {
+                    name: "me".to_string(),
+                    values: values.clone(),
+                },
+                ChartSeries {
+                    name: "you".to_string(),
+                    values,
+                },
+            ],
+            x_label: Some("Day".to_string()),
+            y_label: None,
+        }
+    }
+
+    #[test]
+    fn test_render_chart_should_draw_all_kinds() {
+        for kind in [ChartKind::Line, ChartKind::Bar, ChartKind::Pie] {
+            let svg = render_chart(&args(kind, vec![8000.0, 6500.0, 9000.0])).unwrap();
+            assert!(svg.starts_with("<svg"), "{}", kind);
+            assert!(svg.contains("Steps &lt;per&gt; week"), "{}", kind);
+            assert!(svg.contains("Tue"), "{}", kind);
+            // the missing label is numbered
+            assert!(svg.contains("\n3\n"), "{}", kind);
+        }
+
+        assert!(render_chart(&args(ChartKind::Line, vec![])).is_err());
+        assert!(render_chart(&args(ChartKind::Bar, vec![f64::NAN])).is_err());
+        assert!(render_chart(&args(ChartKind::Pie, vec![-1.0, 2.0])).is_err());
+    }
+}
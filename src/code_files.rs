@@ -0,0 +1,189 @@
+use crate::{
+    code_archive_path, code_archive_url, code_path, code_url, markdown::CodeBlock, tools::CodeFile,
+};
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    io::{Cursor, Write},
+};
+use tokio::fs;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+// (fence languages, file extension), the first language is the canonical one
+const LANGUAGES: &[(&[&str], &str)] = &[
+    (&["python", "py"], "py"),
+    (&["javascript", "js", "node"], "js"),
+    (&["typescript", "ts"], "ts"),
+    (&["jsx"], "jsx"),
+    (&["tsx"], "tsx"),
+    (&["rust", "rs"], "rs"),
+    (&["go", "golang"], "go"),
+    (&["java"], "java"),
+    (&["kotlin", "kt"], "kt"),
+    (&["swift"], "swift"),
+    (&["c"], "c"),
+    (&["cpp", "c++", "cc"], "cpp"),
+    (&["csharp", "cs", "c#"], "cs"),
+    (&["ruby", "rb"], "rb"),
+    (&["php"], "php"),
+    (&["shell", "sh", "bash", "zsh", "console"], "sh"),
+    (&["sql"], "sql"),
+    (&["html"], "html"),
+    (&["css"], "css"),
+    (&["json"], "json"),
+    (&["yaml", "yml"], "yaml"),
+    (&["toml"], "toml"),
+    (&["markdown", "md"], "md"),
+    (&["mermaid"], "mmd"),
+];
+
+/// Store the code blocks as downloadable files, plus a zip archive of all of them if there is
+/// more than one. Returns the files and the archive url.
+pub(crate) async fn save_code_files(
+    device_id: &str,
+    blocks: &[CodeBlock],
+) -> Result<(Vec<CodeFile>, Option<String>)> {
+    if blocks.is_empty() {
+        return Ok((vec![], None));
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let dir = code_path(device_id, &id);
+    let mut files = Vec::with_capacity(blocks.len());
+    for ((name, language), block) in name_files(blocks).into_iter().zip(blocks) {
+        let path = dir.join(&name);
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        fs::write(&path, &block.code).await?;
+        let url = code_url(device_id, &id, &name);
+        files.push(CodeFile::new(name, language, url));
+    }
+
+    if files.len() < 2 {
+        return Ok((files, None));
+    }
+    let entries: Vec<_> = files
+        .iter()
+        .zip(blocks)
+        .map(|(file, block)| (file.name.clone(), block.code.clone()))
+        .collect();
+    let data = tokio::task::spawn_blocking(move || zip_files(&entries)).await??;
+    fs::write(code_archive_path(device_id, &id), data).await?;
+    Ok((files, Some(code_archive_url(device_id, &id))))
+}
+
+/// Unique and safe relative file names for the code blocks, along with their languages.
+fn name_files(blocks: &[CodeBlock]) -> Vec<(String, String)> {
+    let mut used = HashSet::new();
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| {
+            let lang = block.lang.to_lowercase();
+            let name = block
+                .filename
+                .as_deref()
+                .map(sanitize_path)
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| default_name(&lang, i, blocks.len()));
+            let language = if lang.is_empty() {
+                language_of(&name).to_string()
+            } else {
+                lang
+            };
+            (dedupe(name, &mut used), language)
+        })
+        .collect()
+}
+
+fn default_name(lang: &str, index: usize, total: usize) -> String {
+    if lang == "dockerfile" {
+        return "Dockerfile".to_string();
+    }
+    let ext = LANGUAGES
+        .iter()
+        .find(|(langs, _)| langs.contains(&lang))
+        .map(|(_, ext)| *ext)
+        .unwrap_or("txt");
+    if total == 1 {
+        format!("main.{}", ext)
+    } else {
+        format!("snippet-{}.{}", index + 1, ext)
+    }
+}
+
+fn language_of(name: &str) -> &'static str {
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    LANGUAGES
+        .iter()
+        .find(|(_, v)| v.eq_ignore_ascii_case(ext))
+        .map(|(langs, _)| langs[0])
+        .unwrap_or("text")
+}
+
+// the model decides the file names, so never let them escape the directory
+fn sanitize_path(name: &str) -> String {
+    name.split('/')
+        .filter(|v| !v.is_empty() && *v != "." && *v != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn dedupe(name: String, used: &mut HashSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut n = 2;
+    while used.contains(&candidate) {
+        candidate = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && !stem.ends_with('/') => {
+                format!("{}-{}.{}", stem, n, ext)
+            }
+            _ => format!("{}-{}", name, n),
+        };
+        n += 1;
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+fn zip_files(files: &[(String, String)]) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, content) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(content.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::extract_code_blocks;
+
+    #[test]
+    fn test_name_files_should_be_unique_and_safe() {
+        let md = "```rust ../../etc/main.rs\n```\n\n```rust src/../main.rs\n```\n\n```\n// index.html\n```\n\n```python\n```\n\n```\n```\n";
+        let names = name_files(&extract_code_blocks(md));
+        assert_eq!(
+            names,
+            vec![
+                ("etc/main.rs".to_string(), "rust".to_string()),
+                ("src/main.rs".to_string(), "rust".to_string()),
+                ("index.html".to_string(), "html".to_string()),
+                ("snippet-4.py".to_string(), "python".to_string()),
+                ("snippet-5.txt".to_string(), "text".to_string()),
+            ]
+        );
+
+        let blocks = extract_code_blocks("```js a.js\n```\n\n```js a.js\n```\n");
+        let names: Vec<_> = name_files(&blocks).into_iter().map(|v| v.0).collect();
+        assert_eq!(names, vec!["a.js", "a-2.js"]);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::AppState;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use llm_sdk::{AssistantMessage, ChatCompletionMessage, ChatCompletionRequest};
+use serde_json::Value;
+use std::{collections::HashMap, future::Future};
+use tiktoken_rs::cl100k_base_singleton;
+use tracing::{info, warn};
+
+// the latest turns are always sent as they are
+const RECENT_TURNS: usize = 4;
+// for models without a configured budget
+const DEFAULT_BUDGET: usize = 3_000;
+// overhead of the chat format, as counted in the openai cookbook
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_NAME: usize = 1;
+const TOKENS_REPLY_PRIMING: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Turn {
+    user: String,
+    assistant: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Conversation {
+    /// rolling summary of the turns older than the ones kept
+    summary: Option<String>,
+    turns: Vec<Turn>,
+}
+
+/// Max prompt tokens of each model, the rest of its context window is left for the reply.
+#[derive(Debug, Clone)]
+pub(crate) struct ContextBudgets(HashMap<String, usize>);
+
+impl Conversation {
+    /// The history as chat messages, the summary first.
+    pub(crate) fn messages(&self) -> Vec<ChatCompletionMessage> {
+        let summary = self.summary.iter().map(|v| {
+            ChatCompletionMessage::new_system(
+                format!("Summary of our earlier conversation: {}", v),
+                "Ava",
+            )
+        });
+        let turns = self.turns.iter().flat_map(|v| {
+            [
+                ChatCompletionMessage::new_user(&v.user, ""),
+                ChatCompletionMessage::Assistant(AssistantMessage {
+                    content: Some(v.assistant.clone()),
+                    name: None,
+                    tool_calls: vec![],
+                }),
+            ]
+        });
+        summary.chain(turns).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.summary.is_none() && self.turns.is_empty()
+    }
+}
+
+impl Default for ContextBudgets {
+    fn default() -> Self {
+        Self(HashMap::from([
+            // 16k window, 4k reply
+            ("gpt-3.5-turbo-1106".to_string(), 12_000),
+            ("gpt-3.5-turbo-instruct".to_string(), 3_000),
+            // 128k window, kept smaller for cost and latency
+            ("gpt-4-1106-preview".to_string(), 32_000),
+            ("gpt-4-1106-vision-preview".to_string(), 32_000),
+        ]))
+    }
+}
+
+impl ContextBudgets {
+    pub(crate) fn new(overrides: &[(String, usize)]) -> Self {
+        let mut budgets = Self::default();
+        budgets.0.extend(overrides.iter().cloned());
+        budgets
+    }
+
+    pub(crate) fn budget(&self, model: &str) -> usize {
+        self.0.get(model).copied().unwrap_or(DEFAULT_BUDGET)
+    }
+}
+
+impl AppState {
+    /// Build the request with as much of the device's history as the budget of the model allows.
+    /// Over budget, the older turns are summarized and only the recent ones are kept verbatim.
+    pub(crate) async fn with_history(
+        &self,
+        device_id: &str,
+        build: impl Fn(&[ChatCompletionMessage]) -> ChatCompletionRequest,
+    ) -> ChatCompletionRequest {
+        fit_history(
+            &self.conversations,
+            &self.context_budgets,
+            device_id,
+            build,
+            |summary, turns| async move { self.summarize_turns(summary.as_deref(), &turns).await },
+        )
+        .await
+    }
+
+    /// Add the exchange to the device's history, for the next requests to follow up on.
+    pub(crate) fn record_turn(&self, device_id: &str, user: &str, assistant: &str) {
+        self.conversations
+            .entry(device_id.to_string())
+            .or_default()
+            .turns
+            .push(Turn {
+                user: user.to_string(),
+                assistant: assistant.to_string(),
+            });
+    }
+
+    async fn summarize_turns(&self, summary: Option<&str>, turns: &[Turn]) -> Result<String> {
+        let mut content = summary
+            .map(|v| format!("Earlier: {}\n\n", v))
+            .unwrap_or_default();
+        for turn in turns {
+            content.push_str(&format!("User: {}\nAva: {}\n\n", turn.user, turn.assistant));
+        }
+        let messages = vec![
+          ChatCompletionMessage::new_system("I'll summarize our conversation so far in a few short sentences, keeping the facts, names, numbers and decisions needed to carry it on", "Ava"),
+          ChatCompletionMessage::new_user(content, ""),
+        ];
+        let mut res = self
+            .llm
+            .chat_completion(ChatCompletionRequest::new(messages))
+            .await?;
+        res.choices
+            .pop()
+            .and_then(|v| v.message.content)
+            .ok_or_else(|| anyhow!("expect content but no content available"))
+    }
+}
+
+async fn fit_history<S, F>(
+    conversations: &DashMap<String, Conversation>,
+    budgets: &ContextBudgets,
+    device_id: &str,
+    build: impl Fn(&[ChatCompletionMessage]) -> ChatCompletionRequest,
+    summarize: S,
+) -> ChatCompletionRequest
+where
+    S: FnOnce(Option<String>, Vec<Turn>) -> F,
+    F: Future<Output = Result<String>>,
+{
+    let mut conversation = conversations
+        .get(device_id)
+        .map(|v| v.clone())
+        .unwrap_or_default();
+    let req = build(&conversation.messages());
+    let budget = budgets.budget(&model(&req));
+    let tokens = count_tokens(&req);
+    if tokens <= budget {
+        return req;
+    }
+
+    if conversation.turns.len() > RECENT_TURNS {
+        let older = conversation.turns[..conversation.turns.len() - RECENT_TURNS].to_vec();
+        match summarize(conversation.summary.clone(), older.clone()).await {
+            Ok(summary) => {
+                // turns recorded meanwhile are kept, the ones summarized are removed unless
+                // another request did it already
+                if let Some(mut entry) = conversations.get_mut(device_id) {
+                    if entry.turns.starts_with(&older) {
+                        entry.turns.drain(..older.len());
+                        entry.summary = Some(summary);
+                    }
+                    conversation = entry.clone();
+                }
+                info!(
+                    "{} turns of {} summarized, {} tokens over the budget of {}",
+                    older.len(),
+                    device_id,
+                    tokens - budget,
+                    budget
+                );
+            }
+            // the turns are kept, to summarize on the next request
+            Err(e) => warn!("failed to summarize the conversation: {}", e),
+        }
+    }
+
+    // what still doesn't fit is left out of this request only, the oldest first
+    loop {
+        let req = build(&conversation.messages());
+        if conversation.is_empty() || count_tokens(&req) <= budget {
+            return req;
+        }
+        if conversation.turns.is_empty() {
+            conversation.summary = None;
+        } else {
+            conversation.turns.remove(0);
+        }
+    }
+}
+
+/// Tokens of the prompt of the request, with cl100k_base used by all the chat models.
+pub(crate) fn count_tokens(req: &ChatCompletionRequest) -> usize {
+    let bpe = cl100k_base_singleton();
+    let count = |text: &str| bpe.encode_ordinary(text).len();
+    let req = serde_json::to_value(req).unwrap_or_default();
+
+    let messages = req["messages"]
+        .as_array()
+        .map(|v| v.as_slice())
+        .unwrap_or_default();
+    let mut tokens = TOKENS_REPLY_PRIMING;
+    for message in messages.iter().filter_map(Value::as_object) {
+        tokens += TOKENS_PER_MESSAGE;
+        for (key, value) in message {
+            if let Some(value) = value.as_str() {
+                tokens += count(value);
+                if key == "name" {
+                    tokens += TOKENS_PER_NAME;
+                }
+            }
+        }
+    }
+    // tools are given to the model in another format, their json is a close upper bound
+    if let Some(tools) = req.get("tools") {
+        tokens += count(&tools.to_string());
+    }
+    tokens
+}
+
+fn model(req: &ChatCompletionRequest) -> String {
+    serde_json::to_value(req)
+        .ok()
+        .and_then(|v| v["model"].as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// Parse a budget given as "model=tokens".
+pub(crate) fn parse_budget(s: &str) -> Result<(String, usize), String> {
+    let (model, tokens) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expect model=tokens, got {}", s))?;
+    let tokens = tokens
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid tokens in {}: {}", s, e))?;
+    Ok((model.trim().to_string(), tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_tokens_should_follow_the_chat_format() {
+        let req = ChatCompletionRequest::new(vec![
+            ChatCompletionMessage::new_system("You are a helpful assistant.", "Ava"),
+            ChatCompletionMessage::new_user("Hello world", ""),
+        ]);
+        // "system" + 6 + "Ava" (2 + 1 for the name), "user" + 2, 3 per message and 3 for the reply
+        assert_eq!(count_tokens(&req), 1 + 6 + 3 + 1 + 2 + 3 * 2 + 3);
+        assert_eq!(model(&req), "gpt-3.5-turbo-1106");
+
+        let mut conversation = Conversation {
+            summary: Some("We talked about Rust.".to_string()),
+            ..Default::default()
+        };
+        conversation.turns.push(Turn {
+            user: "Hi".to_string(),
+            assistant: "Hello!".to_string(),
+        });
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(messages[2], ChatCompletionMessage::Assistant(_)));
+
+        let budgets = ContextBudgets::new(&[parse_budget("gpt-4-1106-preview = 64000").unwrap()]);
+        assert_eq!(budgets.budget("gpt-4-1106-preview"), 64_000);
+        assert_eq!(budgets.budget("gpt-3.5-turbo-1106"), 12_000);
+        assert!(parse_budget("gpt-4").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fit_history_should_summarize_older_turns_within_budget() {
+        let turn = |i: usize| Turn {
+            user: format!(
+                "Question {} about the trip to Lisbon, what should I pack?",
+                i
+            ),
+            assistant: format!(
+                "Answer {}: light clothes, comfortable shoes and a jacket.",
+                i
+            ),
+        };
+        let conversations = DashMap::new();
+        conversations.insert(
+            "dev".to_string(),
+            Conversation {
+                summary: None,
+                turns: (0..6).map(turn).collect(),
+            },
+        );
+        let budgets = ContextBudgets::new(&[("gpt-3.5-turbo-1106".to_string(), 200)]);
+        let build = |history: &[ChatCompletionMessage]| {
+            let mut messages = vec![ChatCompletionMessage::new_system("I'm Ava", "Ava")];
+            messages.extend_from_slice(history);
+            messages.push(ChatCompletionMessage::new_user("And for Porto?", ""));
+            ChatCompletionRequest::new(messages)
+        };
+
+        // fails: nothing is lost, the request is trimmed to the budget anyway
+        let req = fit_history(&conversations, &budgets, "dev", build, |_, _| async {
+            Err(anyhow!("no network"))
+        })
+        .await;
+        assert!(count_tokens(&req) <= 200);
+        assert_eq!(conversations.get("dev").unwrap().turns.len(), 6);
+
+        // a turn recorded while summarizing is kept
+        let req = fit_history(&conversations, &budgets, "dev", build, |summary, turns| {
+            assert_eq!((summary, turns.len()), (None, 2));
+            conversations.get_mut("dev").unwrap().turns.push(turn(6));
+            async { Ok("We planned a trip to Lisbon.".to_string()) }
+        })
+        .await;
+        assert!(count_tokens(&req) <= 200);
+        let conversation = conversations.get("dev").unwrap();
+        assert_eq!(
+            conversation.summary.as_deref(),
+            Some("We planned a trip to Lisbon.")
+        );
+        assert_eq!(conversation.turns, (2..7).map(turn).collect::<Vec<_>>());
+        let req = serde_json::to_value(&req).unwrap();
+        assert!(req["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .contains("We planned a trip to Lisbon."));
+    }
+}
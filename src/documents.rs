@@ -0,0 +1,511 @@
+use crate::{documents_path, write_atomic, AppState};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, Utc};
+use llm_sdk::{EmbeddingRequest, LlmSdk};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// ~300 tokens, small enough to cite, big enough to keep the context
+const CHUNK_CHARS: usize = 1200;
+// the tail of the previous chunk repeated, so that a sentence cut in two is still found
+const CHUNK_OVERLAP_CHARS: usize = 200;
+const MAX_CHUNKS: usize = 1000;
+const EMBEDDING_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DocumentKind {
+    Pdf,
+    Markdown,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Document {
+    pub(crate) id: String,
+    /// file name of the upload
+    pub(crate) name: String,
+    pub(crate) kind: DocumentKind,
+    /// size of the upload in bytes
+    pub(crate) size: usize,
+    pub(crate) chunks: usize,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DocumentChunk {
+    pub(crate) document_id: String,
+    pub(crate) document_name: String,
+    /// path in the knowledge base, none for uploads
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// position of the chunk in the document, from 0
+    pub(crate) index: usize,
+    pub(crate) text: String,
+    pub(crate) embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SearchHit {
+    pub(crate) chunk: DocumentChunk,
+    /// cosine similarity to the query
+    pub(crate) score: f32,
+}
+
+impl DocumentKind {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        let ext = name.rsplit_once('.')?.1.to_lowercase();
+        match ext.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "md" | "markdown" => Some(Self::Markdown),
+            "txt" | "text" | "csv" | "log" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn icon(&self) -> &'static str {
+        match self {
+            Self::Pdf => "fa-file-pdf",
+            Self::Markdown => "fa-file-lines",
+            Self::Text => "fa-file",
+        }
+    }
+}
+
+impl Document {
+    pub(crate) fn datetime(&self) -> String {
+        self.created_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    /// Human readable size, e.g. "1.2 MB".
+    pub(crate) fn display_size(&self) -> String {
+        match self.size {
+            v if v >= 1024 * 1024 => format!("{:.1} MB", v as f64 / 1024.0 / 1024.0),
+            v if v >= 1024 => format!("{:.1} KB", v as f64 / 1024.0),
+            v => format!("{} B", v),
+        }
+    }
+}
+
+impl AppState {
+    /// Extract, chunk and embed the uploaded file, then add it to the device's index.
+    pub(crate) async fn add_document(
+        &self,
+        device_id: &str,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<Document> {
+        let kind = DocumentKind::from_name(name)
+            .ok_or_else(|| anyhow!("{} is not a pdf, markdown or text file", name))?;
+        let size = data.len();
+        let embedded = embed_file(&self.llm, name, data).await?;
+
+        let doc = Document {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            kind,
+            size,
+            chunks: embedded.len(),
+            created_at: Utc::now(),
+        };
+        let chunks: Vec<_> = embedded
+            .into_iter()
+            .enumerate()
+            .map(|(index, (text, embedding))| DocumentChunk {
+                document_id: doc.id.clone(),
+                document_name: doc.name.clone(),
+                path: None,
+                index,
+                text,
+                embedding,
+            })
+            .collect();
+
+        let _guard = self.documents_lock.lock().await;
+        let dir = documents_path(device_id);
+        if !dir.exists() {
+            fs::create_dir_all(&dir).await?;
+        }
+        // listed first, a document without its chunks can be deleted, chunks without it can't
+        let mut docs = load_documents(&dir).await?;
+        docs.push(doc.clone());
+        write_atomic(
+            &dir.join("documents.json"),
+            &serde_json::to_vec_pretty(&docs)?,
+        )
+        .await?;
+        if let Err(e) = append_chunks(&dir, &chunks).await {
+            docs.pop();
+            write_atomic(
+                &dir.join("documents.json"),
+                &serde_json::to_vec_pretty(&docs)?,
+            )
+            .await?;
+            return Err(e);
+        }
+        self.document_indexes.remove(device_id);
+        info!(
+            "document {} indexed for {}: {} chunks",
+            doc.name, device_id, doc.chunks
+        );
+        Ok(doc)
+    }
+
+    /// Documents uploaded by the device, newest first.
+    pub(crate) async fn documents(&self, device_id: &str) -> Result<Vec<Document>> {
+        let _guard = self.documents_lock.lock().await;
+        let mut docs = load_documents(&documents_path(device_id)).await?;
+        docs.reverse();
+        Ok(docs)
+    }
+
+    /// Remove the document and its chunks, returns false if not found.
+    pub(crate) async fn delete_document(&self, device_id: &str, id: &str) -> Result<bool> {
+        let _guard = self.documents_lock.lock().await;
+        let dir = documents_path(device_id);
+        let mut docs = load_documents(&dir).await?;
+        let len = docs.len();
+        docs.retain(|v| v.id != id);
+        if docs.len() == len {
+            return Ok(false);
+        }
+        let mut chunks = load_chunks(&dir.join("chunks.jsonl")).await?;
+        chunks.retain(|v| v.document_id != id);
+        write_atomic(&dir.join("chunks.jsonl"), &chunks_to_jsonl(&chunks)?).await?;
+        write_atomic(
+            &dir.join("documents.json"),
+            &serde_json::to_vec_pretty(&docs)?,
+        )
+        .await?;
+        self.document_indexes.remove(device_id);
+        Ok(true)
+    }
+
+    /// The chunks of the device's documents and of the knowledge base most similar to the query.
+    pub(crate) async fn search_documents(
+        &self,
+        device_id: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>> {
+        let mut indexes = vec![self.document_index(device_id).await?];
+        if let Some(knowledge) = &self.knowledge {
+            indexes.push(knowledge.index().await?);
+        }
+        if indexes.iter().all(|v| v.is_empty()) {
+            return Ok(vec![]);
+        }
+        let mut res = self
+            .llm
+            .embedding(EmbeddingRequest::new(query.to_string()))
+            .await?;
+        let query = res
+            .data
+            .pop()
+            .ok_or_else(|| anyhow!("expect an embedding for the query"))?;
+        let indexes: Vec<_> = indexes.iter().map(|v| v.as_slice()).collect();
+        let hits = search(&indexes, &query.embedding, limit);
+        info!(
+            "{} document hits for {}, the best scoring {:.3}",
+            hits.len(),
+            device_id,
+            hits.first().map(|v| v.score).unwrap_or_default()
+        );
+        Ok(hits)
+    }
+
+    async fn document_index(&self, device_id: &str) -> Result<Arc<Vec<DocumentChunk>>> {
+        if let Some(index) = self.document_indexes.get(device_id) {
+            return Ok(index.clone());
+        }
+        let _guard = self.documents_lock.lock().await;
+        let index = Arc::new(load_chunks(&documents_path(device_id).join("chunks.jsonl")).await?);
+        self.document_indexes
+            .insert(device_id.to_string(), index.clone());
+        Ok(index)
+    }
+}
+
+async fn load_documents(dir: &Path) -> Result<Vec<Document>> {
+    match fs::read(dir.join("documents.json")).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+pub(crate) async fn load_chunks(path: &Path) -> Result<Vec<DocumentChunk>> {
+    let data = match fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(chunk) => Some(chunk),
+            Err(e) => {
+                warn!("invalid document chunk in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect())
+}
+
+async fn append_chunks(dir: &Path, chunks: &[DocumentChunk]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("chunks.jsonl"))
+        .await?;
+    file.write_all(&chunks_to_jsonl(chunks)?).await?;
+    Ok(())
+}
+
+pub(crate) fn chunks_to_jsonl(chunks: &[DocumentChunk]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for chunk in chunks {
+        serde_json::to_writer(&mut data, chunk)?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+/// Extract the text of the file, split it into chunks and embed them.
+pub(crate) async fn embed_file(
+    llm: &LlmSdk,
+    name: &str,
+    data: Vec<u8>,
+) -> Result<Vec<(String, Vec<f32>)>> {
+    let kind = DocumentKind::from_name(name)
+        .ok_or_else(|| anyhow!("{} is not a pdf, markdown or text file", name))?;
+    let text = tokio::task::spawn_blocking(move || extract_text(kind, &data)).await??;
+    let texts = chunk_text(&text, CHUNK_CHARS, CHUNK_OVERLAP_CHARS);
+    if texts.is_empty() {
+        bail!("no text found in {}", name);
+    }
+    if texts.len() > MAX_CHUNKS {
+        bail!(
+            "{} is too long, at most {} chunks are supported",
+            name,
+            MAX_CHUNKS
+        );
+    }
+
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDING_BATCH) {
+        let mut res = llm
+            .embedding(EmbeddingRequest::new_array(batch.to_vec()))
+            .await?;
+        res.data.sort_by_key(|v| v.index);
+        embeddings.extend(res.data.into_iter().map(|v| v.embedding));
+    }
+    if embeddings.len() != texts.len() {
+        bail!(
+            "expect {} embeddings, got {}",
+            texts.len(),
+            embeddings.len()
+        );
+    }
+    Ok(texts.into_iter().zip(embeddings).collect())
+}
+
+fn extract_text(kind: DocumentKind, data: &[u8]) -> Result<String> {
+    match kind {
+        DocumentKind::Pdf => pdf_extract::extract_text_from_mem(data)
+            .map_err(|e| anyhow!("failed to read the pdf: {}", e)),
+        DocumentKind::Markdown | DocumentKind::Text => {
+            Ok(String::from_utf8_lossy(data).into_owned())
+        }
+    }
+}
+
+/// Split the text at paragraphs into chunks of at most `max_chars`, each starting with the
+/// last `overlap` chars of the previous one. Paragraphs longer than a chunk are split at words.
+pub(crate) fn chunk_text(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    // long paragraphs are cut to leave room for the overlap in front
+    let piece_chars = max_chars.saturating_sub(overlap + 2).max(1);
+    let mut pieces = Vec::new();
+    for paragraph in text.split("\n\n") {
+        let paragraph = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+        if paragraph.is_empty() {
+            continue;
+        }
+        if paragraph.chars().count() <= max_chars {
+            pieces.push(paragraph);
+            continue;
+        }
+        let mut piece = String::new();
+        for word in paragraph.split(' ') {
+            if !piece.is_empty() && piece.chars().count() + word.chars().count() + 1 > piece_chars {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            if !piece.is_empty() {
+                piece.push(' ');
+            }
+            piece.push_str(word);
+        }
+        if !piece.is_empty() {
+            pieces.push(piece);
+        }
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for piece in pieces {
+        let len = piece.chars().count();
+        if !current.is_empty() && current.chars().count() + len + 2 > max_chars {
+            let tail = tail(&current, overlap);
+            chunks.push(std::mem::take(&mut current));
+            if !tail.is_empty() && tail.chars().count() + len + 2 <= max_chars {
+                current = tail;
+            }
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(&piece);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// the last chars of the text, starting at a word
+fn tail(text: &str, chars: usize) -> String {
+    let count = text.chars().count();
+    if chars == 0 || count <= chars {
+        return String::new();
+    }
+    let start = text.char_indices().nth(count - chars).map(|(i, _)| i);
+    let tail = &text[start.unwrap_or(0)..];
+    match tail.split_once(' ') {
+        Some((_, rest)) => format!("…{}", rest),
+        None => String::new(),
+    }
+}
+
+/// The chunks of all the indexes most similar to the query, only those are cloned.
+pub(crate) fn search(indexes: &[&[DocumentChunk]], query: &[f32], limit: usize) -> Vec<SearchHit> {
+    if limit == 0 {
+        return vec![];
+    }
+    let mut scores: Vec<_> = indexes
+        .iter()
+        .enumerate()
+        .flat_map(|(i, index)| {
+            index
+                .iter()
+                .enumerate()
+                .map(move |(j, chunk)| ((i, j), cosine(&chunk.embedding, query)))
+        })
+        .collect();
+    let by_score = |a: &(_, f32), b: &(_, f32)| b.1.total_cmp(&a.1);
+    if scores.len() > limit {
+        scores.select_nth_unstable_by(limit - 1, by_score);
+        scores.truncate(limit);
+    }
+    scores.sort_by(by_score);
+    scores
+        .into_iter()
+        .map(|((i, j), score)| SearchHit {
+            chunk: indexes[i][j].clone(),
+            score,
+        })
+        .collect()
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// Drop the [n] citation marks, they are for reading not listening.
+pub(crate) fn strip_citations(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let (before, after) = rest.split_at(start);
+        out.push_str(before);
+        match after[1..].find(']') {
+            Some(end)
+                if end > 0
+                    && after[1..=end]
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || c == ',' || c == ' ') =>
+            {
+                out.truncate(out.trim_end().len());
+                rest = &after[end + 2..];
+            }
+            _ => {
+                out.push('[');
+                rest = &after[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(name: &str, index: usize, embedding: Vec<f32>) -> DocumentChunk {
+        DocumentChunk {
+            document_id: name.to_string(),
+            document_name: name.to_string(),
+            path: None,
+            index,
+            text: format!("{} {}", name, index),
+            embedding,
+        }
+    }
+
+    #[test]
+    fn test_chunks_should_be_searchable() {
+        let text = "First paragraph.\n\nSecond   paragraph\nwraps.\n\n\n".to_string()
+            + &"word ".repeat(60);
+        let chunks = chunk_text(&text, 100, 20);
+        assert_eq!(chunks[0], "First paragraph.\n\nSecond paragraph wraps.");
+        assert!(chunks.iter().all(|v| v.chars().count() <= 100));
+        // the overlap starts at a word
+        assert!(chunks[2].starts_with("…word"), "{}", chunks[2]);
+        assert_eq!(chunk_text(" \n\n ", 100, 20), Vec::<String>::new());
+
+        let index = vec![
+            chunk("a", 0, vec![1.0, 0.1]),
+            chunk("b", 0, vec![0.6, 0.8]),
+            chunk("c", 0, vec![0.0, 0.0]),
+        ];
+        let knowledge = vec![
+            chunk("d", 0, vec![0.0, 1.0]),
+            chunk("e", 0, vec![-1.0, 0.0]),
+        ];
+        let hits = search(&[&index, &knowledge], &[0.0, 2.0], 3);
+        let names: Vec<_> = hits
+            .iter()
+            .map(|v| v.chunk.document_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["d", "b", "a"]);
+        assert!((hits[1].score - 0.8).abs() < 1e-6);
+        assert!(search(&[&index], &[0.0, 2.0], 0).is_empty());
+
+        assert_eq!(
+            strip_citations("Rust is safe [1] and fast [2, 3]. See [the docs]."),
+            "Rust is safe and fast. See [the docs]."
+        );
+    }
+}
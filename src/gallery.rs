@@ -0,0 +1,249 @@
+use crate::{
+    gallery_path, image_path, thumbnail_path,
+    tools::{GeneratedImage, ImagePreview},
+    write_atomic, AppState,
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::warn;
+
+// replies with images kept for each device to edit, the oldest are forgotten first
+const MAX_IMAGE_REPLIES: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GalleryEntry {
+    /// image id
+    pub(crate) id: String,
+    /// image url
+    pub(crate) url: String,
+    /// downscaled preview of the image
+    pub(crate) preview: Option<ImagePreview>,
+    /// revised prompt of the image
+    pub(crate) prompt: String,
+    /// the chat message id the image was generated for
+    pub(crate) conversation: String,
+    /// what the user said in that chat message
+    pub(crate) input: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+impl GalleryEntry {
+    fn new(image: &GeneratedImage, conversation: &str, input: &str) -> Self {
+        Self {
+            id: image.id.clone(),
+            url: image.url.clone(),
+            preview: image.preview.clone(),
+            prompt: image.prompt.clone(),
+            conversation: conversation.to_string(),
+            input: input.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    pub(crate) fn datetime(&self) -> String {
+        self.created_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    pub(crate) fn matches(&self, query: &str) -> bool {
+        query.is_empty() || self.prompt.to_lowercase().contains(&query.to_lowercase())
+    }
+}
+
+impl AppState {
+    /// Remember the images as the ones of the reply, the last generated ones, and add them to
+    /// the gallery.
+    pub(crate) async fn record_images(
+        &self,
+        device_id: &str,
+        conversation: &str,
+        input: &str,
+        images: &[GeneratedImage],
+    ) -> Result<()> {
+        {
+            let mut replies = self.reply_images.entry(device_id.to_string()).or_default();
+            replies.retain(|(id, _)| id != conversation);
+            if replies.len() >= MAX_IMAGE_REPLIES {
+                replies.pop_front();
+            }
+            replies.push_back((conversation.to_string(), images.to_vec()));
+        }
+
+        let mut data = Vec::new();
+        for image in images {
+            serde_json::to_writer(&mut data, &GalleryEntry::new(image, conversation, input))?;
+            data.push(b'\n');
+        }
+
+        let _guard = self.gallery_lock.lock().await;
+        let path = gallery_path(device_id);
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(&data).await?;
+        Ok(())
+    }
+
+    /// The zero-based index-th image of the reply to edit, or of the last reply with images if
+    /// none is given.
+    pub(crate) fn image_to_edit(
+        &self,
+        device_id: &str,
+        reply_id: Option<&str>,
+        index: usize,
+    ) -> Result<GeneratedImage> {
+        let replies = self.reply_images.get(device_id);
+        let images = match (reply_id, replies.as_deref().and_then(|v| v.back())) {
+            (None, None) => bail!("no previous image to edit, please draw one first"),
+            (None, Some((_, images))) => images,
+            (Some(reply_id), _) => replies
+                .iter()
+                .flat_map(|v| v.iter())
+                .find(|(id, _)| id == reply_id)
+                .map(|(_, images)| images)
+                .ok_or_else(|| {
+                    anyhow!("that image is no longer available to edit, please draw it again")
+                })?,
+        };
+        match images.get(index) {
+            Some(image) => Ok(image.clone()),
+            None if images.is_empty() => {
+                bail!("that image has been deleted, please draw a new one")
+            }
+            None => bail!(
+                "there is no image {} to edit, that reply only has {}",
+                index + 1,
+                images.len()
+            ),
+        }
+    }
+
+    /// All the images generated by the device, newest first.
+    pub(crate) async fn gallery(&self, device_id: &str) -> Result<Vec<GalleryEntry>> {
+        let _guard = self.gallery_lock.lock().await;
+        load_gallery(device_id).await
+    }
+
+    /// Delete the image from the gallery and the storage, returns false if not found.
+    pub(crate) async fn delete_gallery_image(&self, device_id: &str, id: &str) -> Result<bool> {
+        let _guard = self.gallery_lock.lock().await;
+        let mut entries = load_gallery(device_id).await?;
+        let len = entries.len();
+        entries.retain(|v| v.id != id);
+        if entries.len() == len {
+            return Ok(false);
+        }
+
+        let mut data = Vec::new();
+        for entry in entries.iter().rev() {
+            serde_json::to_writer(&mut data, entry)?;
+            data.push(b'\n');
+        }
+        write_atomic(&gallery_path(device_id), &data).await?;
+
+        for path in [image_path(device_id, id), thumbnail_path(device_id, id)] {
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("failed to remove {}: {}", path.display(), e);
+            }
+        }
+        if let Some(mut replies) = self.reply_images.get_mut(device_id) {
+            for (_, images) in replies.iter_mut() {
+                images.retain(|v| v.id != id);
+            }
+        }
+        Ok(true)
+    }
+}
+
+async fn load_gallery(device_id: &str) -> Result<Vec<GalleryEntry>> {
+    let data = match fs::read_to_string(gallery_path(device_id)).await {
+        Ok(data) => data,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut entries: Vec<GalleryEntry> = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("invalid gallery entry for {}: {}", device_id, e);
+                None
+            }
+        })
+        .collect();
+    entries.reverse();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn state() -> AppState {
+        AppState::for_test("http://127.0.0.1:0", "token")
+    }
+
+    fn image(id: &str, prompt: &str) -> GeneratedImage {
+        GeneratedImage::new(id, format!("/assets/image/d/{}.png", id), prompt)
+    }
+
+    #[tokio::test]
+    async fn test_image_to_edit_should_keep_the_recent_replies() {
+        let state = state();
+        let device_id = &format!("test-{}", Uuid::new_v4());
+        let err = state.image_to_edit(device_id, None, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no previous image to edit, please draw one first"
+        );
+
+        for i in 0..=MAX_IMAGE_REPLIES {
+            let images = [
+                image(&format!("{}-a", i), "a cat"),
+                image(&format!("{}-b", i), "a dog"),
+            ];
+            state
+                .record_images(device_id, &i.to_string(), "draw", &images)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            state.reply_images.get(device_id).unwrap().len(),
+            MAX_IMAGE_REPLIES
+        );
+        let last = MAX_IMAGE_REPLIES.to_string();
+        assert_eq!(
+            state.image_to_edit(device_id, None, 1).unwrap().id,
+            format!("{}-b", last)
+        );
+        assert_eq!(
+            state.image_to_edit(device_id, Some("1"), 0).unwrap().id,
+            "1-a"
+        );
+
+        // the oldest reply is forgotten, an index past the images isn't the same as no image
+        let err = state.image_to_edit(device_id, Some("0"), 0).unwrap_err();
+        assert!(err.to_string().contains("no longer available"), "{}", err);
+        let err = state.image_to_edit(device_id, Some("1"), 2).unwrap_err();
+        assert!(err.to_string().contains("no image 3"), "{}", err);
+
+        for id in ["1-a", "1-b"] {
+            assert!(state.delete_gallery_image(device_id, id).await.unwrap());
+        }
+        let err = state.image_to_edit(device_id, Some("1"), 0).unwrap_err();
+        assert!(err.to_string().contains("deleted"), "{}", err);
+        fs::remove_file(gallery_path(device_id)).await.unwrap();
+    }
+}
@@ -18,7 +18,10 @@ pub async fn events_handler(
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
     info!("user {} connected", context.device_id);
-    sse_handler(context, &state.events).await
+    let ret = sse_handler(context, &state.events).await;
+    // the reminders that came due while the device was away can be fired now
+    state.reminders_changed.notify_one();
+    ret
 }
 
 async fn sse_handler(
@@ -45,6 +48,8 @@ async fn sse_handler(
                 AssistantEvent::Input(v) => ("input", v.id.clone()),
                 AssistantEvent::ReplySkeleton(_) => ("reply_skeleton", "".to_string()),
                 AssistantEvent::Reply(v) => ("reply", v.id.clone()),
+                AssistantEvent::ReplyBlock(v) => ("reply_block", v.event_id()),
+                AssistantEvent::Reminder(v) => ("reminder", v.id.clone()),
             };
             let data: String = v.into();
             Event::default().data(data).event(event).id(id)
@@ -0,0 +1,88 @@
+use crate::{code_root, error::AppError};
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use std::path::Component;
+
+/// Serve a file written by `write_code` as a download. The model picks its name and so its
+/// extension, so it's never served as something the browser would render in our origin.
+pub async fn code_file_handler(Path(path): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let path = std::path::Path::new(path.trim_start_matches('/'));
+    if !path.components().all(|v| matches!(v, Component::Normal(_))) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let data = match tokio::fs::read(code_root().join(path)).await {
+        Ok(data) => data,
+        Err(_) => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let name = path
+        .file_name()
+        .map(|v| {
+            v.to_string_lossy()
+                .chars()
+                .map(|c| match c {
+                    '"' | '\\' => '_',
+                    c if c.is_ascii_graphic() || c == ' ' => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    let content_type = if name.ends_with(".zip") {
+        "application/zip"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_code_file_handler_should_serve_downloads() {
+        let device_id = Uuid::new_v4().to_string();
+        let dir = code_root().join(&device_id).join("1");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("index.html"), "<script>alert(1)</script>")
+            .await
+            .unwrap();
+
+        let res = code_file_handler(Path(format!("{}/1/index.html", device_id)))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            res.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"index.html\""
+        );
+
+        let res = code_file_handler(Path(format!("{}/1/../1/index.html", device_id)))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        tokio::fs::remove_dir_all(code_root().join(&device_id))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{documents::Document, error::AppError, extractors::AppContext, AppState};
+use anyhow::anyhow;
+use askama::Template;
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Debug, Template)]
+#[template(path = "documents.html.j2")]
+struct DocumentsTemplate {
+    documents: Vec<Document>,
+}
+
+pub async fn documents_page(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let documents = state.documents(&context.device_id).await?;
+    Ok(DocumentsTemplate { documents })
+}
+
+pub async fn upload_document_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    mut data: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    let mut file = None;
+    while let Some(field) = data.next_field().await? {
+        if field.name() == Some("file") {
+            let name = field.file_name().unwrap_or_default().to_string();
+            file = Some((name, field.bytes().await?));
+        }
+    }
+    let Some((name, data)) = file else {
+        return Err(anyhow!("expected a file field"))?;
+    };
+
+    info!("indexing {} ({} bytes) for {}", name, data.len(), device_id);
+    match state.add_document(device_id, &name, data.to_vec()).await {
+        Ok(doc) => Ok((
+            StatusCode::OK,
+            Json(json!({"status": "indexed", "id": doc.id, "chunks": doc.chunks})),
+        )),
+        Err(e) => Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "error": e.to_string()})),
+        )),
+    }
+}
+
+pub async fn delete_document_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    if state.delete_document(device_id, &id).await? {
+        info!("document {} deleted for {}", id, device_id);
+        Ok((StatusCode::OK, Json(json!({"status": "deleted"}))))
+    } else {
+        Ok((StatusCode::NOT_FOUND, Json(json!({"status": "not_found"}))))
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{error::AppError, extractors::AppContext, gallery::GalleryEntry, AppState};
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+const PAGE_SIZE: usize = 24;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GalleryQuery {
+    #[serde(default)]
+    page: Option<usize>,
+    #[serde(default)]
+    q: Option<String>,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "gallery.html.j2")]
+struct GalleryTemplate {
+    entries: Vec<GalleryEntry>,
+    query: String,
+    page: usize,
+    total_pages: usize,
+    total: usize,
+}
+
+pub async fn gallery_page(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GalleryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let q = query.q.unwrap_or_default().trim().to_string();
+    let entries: Vec<_> = state
+        .gallery(&context.device_id)
+        .await?
+        .into_iter()
+        .filter(|v| v.matches(&q))
+        .collect();
+
+    let total = entries.len();
+    let total_pages = total.div_ceil(PAGE_SIZE).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, total_pages);
+    let entries = entries
+        .into_iter()
+        .skip((page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .collect();
+
+    Ok(GalleryTemplate {
+        entries,
+        query: q,
+        page,
+        total_pages,
+        total,
+    })
+}
+
+pub async fn delete_gallery_image_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    if state.delete_gallery_image(device_id, &id).await? {
+        info!("image {} deleted for {}", id, device_id);
+        Ok((StatusCode::OK, Json(json!({"status": "deleted"}))))
+    } else {
+        Ok((StatusCode::NOT_FOUND, Json(json!({"status": "not_found"}))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gallery_path, tools::GeneratedImage};
+    use axum::{body::HttpBody, response::Response};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_gallery_page_should_page_and_search_prompts() {
+        let (state, context) = gallery_state();
+        let images: Vec<_> = (0..PAGE_SIZE + 6)
+            .map(|i| {
+                let prompt = if i % 10 == 0 {
+                    "A Sunset over the sea"
+                } else {
+                    "a cat"
+                };
+                GeneratedImage::new(format!("img-{}", i), "/a.png", prompt)
+            })
+            .collect();
+        state
+            .record_images(&context.device_id, "1", "draw", &images)
+            .await
+            .unwrap();
+
+        // a page past the end is the last one, with the oldest images
+        let html = page(&state, &context, Some(5), None).await;
+        assert!(html.contains("<span>2 / 2</span>"), "{}", html);
+        assert_eq!(html.matches(r#"id="gallery-"#).count(), 6);
+        assert!(html.contains(r#"id="gallery-img-0""#));
+
+        let html = page(&state, &context, None, Some(" SUNSET ")).await;
+        assert!(html.contains("3 image(s)"), "{}", html);
+        assert_eq!(html.matches(r#"id="gallery-"#).count(), 3);
+        assert!(!html.contains(r#"id="gallery-img-1""#));
+
+        tokio::fs::remove_file(gallery_path(&context.device_id))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_gallery_image_should_only_delete_own_images() {
+        let (state, context) = gallery_state();
+        let other = AppContext {
+            device_id: format!("test-{}", Uuid::new_v4()),
+        };
+        let image = GeneratedImage::new("mine", "/a.png", "a cat");
+        state
+            .record_images(&context.device_id, "1", "draw", &[image])
+            .await
+            .unwrap();
+
+        for (context, id) in [(&context, "unknown"), (&other, "mine")] {
+            let res = delete(&state, context, id).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(state.gallery(&context.device_id).await.unwrap().len(), 1);
+
+        let res = delete(&state, &context, "mine").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.gallery(&context.device_id).await.unwrap().is_empty());
+
+        tokio::fs::remove_file(gallery_path(&context.device_id))
+            .await
+            .unwrap();
+    }
+
+    fn gallery_state() -> (Arc<AppState>, AppContext) {
+        let context = AppContext {
+            device_id: format!("test-{}", Uuid::new_v4()),
+        };
+        (
+            Arc::new(AppState::for_test("http://127.0.0.1:0", "token")),
+            context,
+        )
+    }
+
+    async fn page(
+        state: &Arc<AppState>,
+        context: &AppContext,
+        page: Option<usize>,
+        q: Option<&str>,
+    ) -> String {
+        let query = GalleryQuery {
+            page,
+            q: q.map(|v| v.to_string()),
+        };
+        let res = gallery_page(context.clone(), State(state.clone()), Query(query))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        body(res).await
+    }
+
+    async fn delete(state: &Arc<AppState>, context: &AppContext, id: &str) -> Response {
+        delete_gallery_image_handler(context.clone(), State(state.clone()), Path(id.to_string()))
+            .await
+            .into_response()
+    }
+
+    async fn body(res: Response) -> String {
+        let mut body = res.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(data).unwrap()
+    }
+}
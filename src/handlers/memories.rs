@@ -0,0 +1,99 @@
+use crate::{
+    error::AppError,
+    extractors::AppContext,
+    memories::Memory,
+    tools::{AssistantTool, ForgetArgs, RecallArgs, RememberArgs},
+    AppState,
+};
+use anyhow::bail;
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Debug, Template)]
+#[template(path = "memories.html.j2")]
+struct MemoriesTemplate {
+    memories: Vec<Memory>,
+}
+
+pub async fn memories_page(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let memories = state.memories(&context.device_id).await?;
+    Ok(MemoriesTemplate { memories })
+}
+
+pub async fn delete_memory_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    if state.delete_memory(device_id, &id).await? {
+        info!("memory {} deleted for {}", id, device_id);
+        Ok((StatusCode::OK, Json(json!({"status": "deleted"}))))
+    } else {
+        Ok((StatusCode::NOT_FOUND, Json(json!({"status": "not_found"}))))
+    }
+}
+
+/// Run the memory tool for the device, returns the reply for the user.
+pub(super) async fn manage_memories(
+    state: &AppState,
+    device_id: &str,
+    tool: AssistantTool,
+    arguments: &str,
+) -> anyhow::Result<String> {
+    match tool {
+        AssistantTool::Remember => {
+            let args: RememberArgs = serde_json::from_str(arguments)?;
+            state.add_memory(device_id, &args.content).await?;
+            Ok("Got it, I'll remember that.".to_string())
+        }
+        AssistantTool::Forget => {
+            let args: ForgetArgs = serde_json::from_str(arguments)?;
+            if args.content.trim().is_empty() {
+                bail!("nothing to forget");
+            }
+            let forgotten = state.forget_memories(device_id, &args.content).await?;
+            Ok(match forgotten.len() {
+                0 => format!(
+                    "I don't remember anything about \"{}\".",
+                    args.content.trim()
+                ),
+                1 => format!("OK, I've forgotten that {}.", forgotten[0].content),
+                n => format!("OK, I've forgotten {} things about that.", n),
+            })
+        }
+        AssistantTool::Recall => {
+            let args: RecallArgs = serde_json::from_str(arguments)?;
+            let query = args.query.as_deref().filter(|v| !v.trim().is_empty());
+            let memories: Vec<_> = state
+                .memories(device_id)
+                .await?
+                .into_iter()
+                .filter(|v| query.map(|q| v.matches(q)).unwrap_or(true))
+                .collect();
+            if memories.is_empty() {
+                return Ok(match query {
+                    Some(q) => format!("I don't remember anything about \"{}\".", q),
+                    None => "You haven't asked me to remember anything yet.".to_string(),
+                });
+            }
+            let items: Vec<_> = memories
+                .iter()
+                .map(|v| format!("- {}", v.content))
+                .collect();
+            Ok(format!("Here's what I remember:\n\n{}", items.join("\n")))
+        }
+        _ => bail!("{} is not a memory tool", tool),
+    }
+}
@@ -1,12 +1,31 @@
 mod assistant;
 mod chats;
+mod code_files;
 mod common;
+mod documents;
+mod gallery;
+mod memories;
+mod reminders;
+mod settings;
+mod translation;
 
 pub use assistant::*;
 pub use chats::*;
+pub use code_files::*;
 pub use common::*;
+pub use documents::*;
+pub use gallery::*;
+pub use memories::*;
+pub use reminders::*;
+pub use settings::*;
+pub use translation::*;
 
-use crate::tools::{DrawImageResult, WriteCodeResult};
+use crate::{
+    markdown::{md2html, ThemedHighlighter},
+    preferences::AudioFormat,
+    reminders::{Reminder, ReminderKind},
+    tools::{AudioClip, DrawChartResult, DrawImageResult, RunCodeResult, WriteCodeResult},
+};
 use askama::Template;
 use chrono::Local;
 use derive_more::From;
@@ -20,6 +39,8 @@ pub(crate) enum AssistantEvent {
     Input(ChatInputEvent),
     ReplySkeleton(ChatReplySkeletonEvent),
     Reply(ChatReplyEvent),
+    ReplyBlock(ChatReplyBlockEvent),
+    Reminder(ReminderEvent),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -45,6 +66,8 @@ pub(crate) struct ChatInputSkeletonEvent {
 pub(crate) struct ChatInputEvent {
     id: String,
     content: String,
+    translation: Option<String>,
+    image: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -59,22 +82,54 @@ pub(crate) struct ChatReplySkeletonEvent {
 #[template(path = "events/chat_reply.html.j2")]
 pub(crate) struct ChatReplyEvent {
     id: String,
+    blocks: Vec<ChatReplyBlockEvent>,
+}
+
+/// A single block of a reply, could be updated on its own by sending it again.
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "events/chat_reply_block.html.j2")]
+pub(crate) struct ChatReplyBlockEvent {
+    reply_id: String,
+    index: usize,
     data: ChatReplyData,
 }
 
+/// A reminder or timer going off, pushed without any input from the user.
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "events/reminder.html.j2")]
+pub(crate) struct ReminderEvent {
+    id: String,
+    kind: ReminderKind,
+    message: String,
+    time: String,
+    audio: Option<AudioClip>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, From)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub(crate) enum ChatReplyData {
     Speech(SpeechResult),
     Image(DrawImageResult),
     Markdown(WriteCodeResult),
+    CodeOutput(RunCodeResult),
+    Chart(DrawChartResult),
+    Failed(FailedBlock),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "blocks/speech.html.j2")]
 pub(crate) struct SpeechResult {
     text: String,
+    html: String,
     url: String,
+    format: AudioFormat,
+}
+
+/// A block that couldn't be generated, the rest of the reply still stands.
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "blocks/failed.html.j2")]
+pub(crate) struct FailedBlock {
+    message: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumString, Display)]
@@ -85,14 +140,36 @@ pub(crate) enum AssistantStep {
     UploadAudio,
     #[strum(serialize = "Transcribing audio")]
     Transcription,
+    #[strum(serialize = "Translating")]
+    Translation,
+    #[strum(serialize = "Looking at the image")]
+    Vision,
     #[strum(serialize = "Thinking hard")]
     Thinking,
     #[strum(serialize = "Organizing answer")]
     ChatCompletion,
     #[strum(serialize = "Drawing image")]
     DrawImage,
+    #[strum(serialize = "Editing image")]
+    EditImage,
     #[strum(serialize = "Writing code")]
     WriteCode,
+    #[strum(serialize = "Running code")]
+    RunCode,
+    #[strum(serialize = "Drawing chart")]
+    DrawChart,
+    #[strum(serialize = "Checking your tasks")]
+    Tasks,
+    #[strum(serialize = "Reading the page")]
+    FetchUrl,
+    #[strum(serialize = "Calculating")]
+    Calculate,
+    #[strum(serialize = "Checking your reminders")]
+    Reminders,
+    #[strum(serialize = "Searching your documents")]
+    SearchDocuments,
+    #[strum(serialize = "Checking what I remember")]
+    Memories,
     #[strum(serialize = "Generating speech")]
     Speech,
 }
@@ -113,6 +190,26 @@ impl ChatInputEvent {
         Self {
             id: id.into(),
             content: content.into(),
+            translation: None,
+            image: None,
+        }
+    }
+
+    pub fn with_image(mut self, url: impl Into<String>) -> Self {
+        self.image = Some(url.into());
+        self
+    }
+
+    pub fn new_with_translation(
+        id: impl Into<String>,
+        content: impl Into<String>,
+        translation: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            content: content.into(),
+            translation: Some(translation.into()),
+            image: None,
         }
     }
 }
@@ -129,23 +226,81 @@ impl ChatReplySkeletonEvent {
 
 impl ChatReplyEvent {
     pub fn new(id: impl Into<String>, data: impl Into<ChatReplyData>) -> Self {
+        Self::new_with_blocks(id, vec![data.into()])
+    }
+
+    pub fn new_with_blocks(id: impl Into<String>, blocks: Vec<ChatReplyData>) -> Self {
+        let id = id.into();
+        let blocks = blocks
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| ChatReplyBlockEvent::new(&id, index, data))
+            .collect();
+        Self { id, blocks }
+    }
+}
+
+impl ChatReplyBlockEvent {
+    pub fn new(reply_id: impl Into<String>, index: usize, data: impl Into<ChatReplyData>) -> Self {
         Self {
-            id: id.into(),
+            reply_id: reply_id.into(),
+            index,
             data: data.into(),
         }
     }
+
+    /// event id of the block, in the format of `reply_id:index`
+    pub fn event_id(&self) -> String {
+        format!("{}:{}", self.reply_id, self.index)
+    }
+}
+
+impl ReminderEvent {
+    pub fn new(reminder: &Reminder) -> Self {
+        Self {
+            id: reminder.id.clone(),
+            kind: reminder.kind,
+            message: reminder.announcement(),
+            time: reminder.due_time(),
+            audio: reminder.audio.clone(),
+        }
+    }
+
+    /// font awesome icon for the kind of the reminder
+    pub fn icon(&self) -> &'static str {
+        match self.kind {
+            ReminderKind::Reminder => "bell",
+            ReminderKind::Timer => "stopwatch",
+        }
+    }
 }
 
 impl SpeechResult {
-    fn new(text: impl Into<String>, url: impl Into<String>) -> Self {
+    fn new(
+        hl: &ThemedHighlighter,
+        text: impl Into<String>,
+        url: impl Into<String>,
+        format: AudioFormat,
+    ) -> Self {
+        let text = text.into();
         Self {
-            text: text.into(),
+            html: md2html(&text, hl),
+            text,
             url: url.into(),
+            format,
         }
     }
 
-    fn new_text_only(text: impl Into<String>) -> Self {
-        Self::new(text, "".to_string())
+    fn new_text_only(hl: &ThemedHighlighter, text: impl Into<String>) -> Self {
+        Self::new(hl, text, "".to_string(), AudioFormat::default())
+    }
+}
+
+impl FailedBlock {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
     }
 }
 
@@ -157,6 +312,8 @@ impl From<AssistantEvent> for String {
             AssistantEvent::Input(v) => v.into(),
             AssistantEvent::ReplySkeleton(v) => v.into(),
             AssistantEvent::Reply(v) => v.into(),
+            AssistantEvent::ReplyBlock(v) => v.into(),
+            AssistantEvent::Reminder(v) => v.into(),
         }
     }
 }
@@ -190,3 +347,15 @@ impl From<ChatReplyEvent> for String {
         event.render().unwrap()
     }
 }
+
+impl From<ChatReplyBlockEvent> for String {
+    fn from(event: ChatReplyBlockEvent) -> Self {
+        event.render().unwrap()
+    }
+}
+
+impl From<ReminderEvent> for String {
+    fn from(event: ReminderEvent) -> Self {
+        event.render().unwrap()
+    }
+}
@@ -0,0 +1,170 @@
+use super::{assistant::synthesize, ReminderEvent};
+use crate::{
+    reminders::{after_delay, due_at, format_duration, Reminder, ReminderKind, MAX_FIRE_ATTEMPTS},
+    tools::{AssistantTool, CancelReminderArgs, SetReminderArgs, SetTimerArgs},
+    AppState,
+};
+use anyhow::bail;
+use chrono::{DateTime, Local, Utc};
+use futures::future;
+use std::sync::Arc;
+use tokio::time;
+use tracing::{info, warn};
+
+// wait before reading the reminders file again after it failed
+const LOAD_RETRY_SECONDS: i64 = 30;
+
+/// Fire the due reminders of all devices, forever. It sleeps until the next one is due, or until
+/// the reminders change. A reminder stays pending until its device is connected to receive it.
+pub async fn reminder_scheduler(state: Arc<AppState>) {
+    loop {
+        let now = Utc::now();
+        let next = match fire_due(&state, now).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("failed to load reminders: {}", e);
+                Some(now + chrono::Duration::seconds(LOAD_RETRY_SECONDS))
+            }
+        };
+        let due = async {
+            match next {
+                Some(at) => time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await,
+                // only a change can bring a reminder
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = due => {}
+            _ = state.reminders_changed.notified() => {}
+        }
+    }
+}
+
+/// Fire the reminders due by now, returns when the next one is due. The due ones of devices
+/// not connected are left out, connecting wakes the scheduler up.
+async fn fire_due(state: &AppState, now: DateTime<Utc>) -> anyhow::Result<Option<DateTime<Utc>>> {
+    for reminder in state.due_reminders(now).await? {
+        let id = reminder.id.clone();
+        if let Err(e) = fire(state, reminder).await {
+            warn!("failed to fire reminder {}: {}", id, e);
+            if !state.retry_reminder(&id, now).await? {
+                warn!(
+                    "reminder {} given up after {} attempts",
+                    id, MAX_FIRE_ATTEMPTS
+                );
+            }
+        }
+    }
+    state.next_reminder_at(now).await
+}
+
+async fn fire(state: &AppState, mut reminder: Reminder) -> anyhow::Result<()> {
+    let Some(sender) = state.events.get(&reminder.device_id).map(|v| v.clone()) else {
+        return Ok(());
+    };
+    if sender.receiver_count() == 0 {
+        return Ok(());
+    }
+
+    if reminder.audio.is_none() {
+        let prefs = state.preferences(&reminder.device_id).await;
+        let text = reminder.announcement();
+        match synthesize(&state.llm, &reminder.device_id, &text, &prefs).await {
+            Ok(audio) => reminder.audio = Some(audio),
+            // a reminder without sound is still better than no reminder
+            Err(e) if reminder.attempts + 1 >= MAX_FIRE_ATTEMPTS => {
+                warn!("failed to synthesize reminder {}: {}", reminder.id, e)
+            }
+            Err(e) => return Err(e),
+        }
+        if !state.update_reminder(&reminder).await? {
+            info!("reminder {} cancelled before it fired", reminder.id);
+            return Ok(());
+        }
+    }
+
+    let send = || sender.send(ReminderEvent::new(&reminder).into()).is_ok();
+    if state.send_reminder(&reminder.id, send).await? {
+        info!("reminder {} fired for {}", reminder.id, reminder.device_id);
+    }
+    Ok(())
+}
+
+/// Run the reminder tool for the device, returns the reply for the user.
+pub(super) async fn manage_reminders(
+    state: &AppState,
+    device_id: &str,
+    tool: AssistantTool,
+    arguments: &str,
+) -> anyhow::Result<String> {
+    match tool {
+        AssistantTool::SetReminder => {
+            let args: SetReminderArgs = serde_json::from_str(arguments)?;
+            let due = due_at(Local::now(), args.delay_seconds, args.at.as_deref())?;
+            let reminder = Reminder::new(device_id, ReminderKind::Reminder, &args.message, due);
+            let when = match args.delay_seconds {
+                Some(secs) => format!("in {}", format_duration(secs)),
+                None => format!("at {}", reminder.due_time()),
+            };
+            state.add_reminder(reminder).await?;
+            Ok(format!("OK, I'll remind you to {} {}.", args.message, when))
+        }
+        AssistantTool::SetTimer => {
+            let args: SetTimerArgs = serde_json::from_str(arguments)?;
+            if args.duration_seconds == 0 {
+                bail!("the timer needs a duration");
+            }
+            let due = after_delay(Utc::now(), args.duration_seconds)?;
+            let reminder = Reminder::new(device_id, ReminderKind::Timer, &args.label, due);
+            state.add_reminder(reminder).await?;
+            let label = if args.label.is_empty() {
+                String::new()
+            } else {
+                format!(" for {}", args.label)
+            };
+            Ok(format!(
+                "Timer set{}, {} starting now.",
+                label,
+                format_duration(args.duration_seconds)
+            ))
+        }
+        AssistantTool::ListReminders => {
+            let reminders = state.reminders(device_id).await?;
+            if reminders.is_empty() {
+                return Ok("You have no reminders or timers.".to_string());
+            }
+            let items: Vec<_> = reminders.iter().map(describe).collect();
+            Ok(format!("Coming up:\n\n{}", items.join("\n")))
+        }
+        AssistantTool::CancelReminder => {
+            let args: CancelReminderArgs = serde_json::from_str(arguments)?;
+            let query = args.message.as_deref().filter(|v| !v.trim().is_empty());
+            let cancelled = state.cancel_reminders(device_id, query).await?;
+            Ok(match (cancelled.len(), query) {
+                (0, Some(q)) => format!("I couldn't find a reminder or timer for \"{}\".", q),
+                (0, None) => "You have no reminders or timers to cancel.".to_string(),
+                (1, _) => format!("Cancelled {}.", describe_short(&cancelled[0])),
+                (n, _) => format!("Cancelled {} reminders and timers.", n),
+            })
+        }
+        _ => bail!("{} is not a reminder tool", tool),
+    }
+}
+
+fn describe(reminder: &Reminder) -> String {
+    let left = (reminder.due_at - Utc::now()).num_seconds().max(0) as u64;
+    format!(
+        "- {} at {} ({} left)",
+        describe_short(reminder),
+        reminder.due_time(),
+        format_duration(left)
+    )
+}
+
+fn describe_short(reminder: &Reminder) -> String {
+    match (reminder.kind, reminder.message.is_empty()) {
+        (ReminderKind::Reminder, _) => format!("the reminder to {}", reminder.message),
+        (ReminderKind::Timer, true) => "the timer".to_string(),
+        (ReminderKind::Timer, false) => format!("the {} timer", reminder.message),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::{error::AppError, extractors::AppContext, preferences::Preferences, AppState};
+use axum::{extract::State, response::IntoResponse, Json};
+use std::sync::Arc;
+use tracing::info;
+
+pub async fn get_settings_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    Json(state.preferences(&context.device_id).await)
+}
+
+pub async fn update_settings_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Json(prefs): Json<Preferences>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.save_preferences(&context.device_id, prefs).await?;
+    info!("preferences updated for {}: {:?}", context.device_id, prefs);
+    Ok(Json(prefs))
+}
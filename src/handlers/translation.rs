@@ -0,0 +1,121 @@
+use crate::{error::AppError, extractors::AppContext, tools::TranslationModeArgs, AppState};
+use axum::{extract::State, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+const DEFAULT_TARGET_LANGUAGE: &str = "English";
+// the longest utterance taken as the command
+const MAX_STOP_WORDS: usize = 8;
+const FILLERS: &[&str] = &["please", "ok", "okay", "hey", "ava", "now", "just", "and"];
+const STOP_VERBS: &[&str] = &["stop", "end", "exit", "quit", "leave", "disable", "cancel"];
+
+pub async fn get_translation_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let target = state
+        .translations
+        .get(&context.device_id)
+        .map(|v| v.clone());
+    translation_mode(target)
+}
+
+pub async fn translation_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    Json(args): Json<TranslationModeArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    let target = set_translation_mode(&state, &context.device_id, args);
+    info!("translation mode for {}: {:?}", context.device_id, target);
+    Ok(translation_mode(target))
+}
+
+fn translation_mode(target: Option<String>) -> Json<serde_json::Value> {
+    Json(json!({
+        "enabled": target.is_some(),
+        "target_language": target,
+    }))
+}
+
+/// Update the translation mode of the device, returns the target language if enabled.
+pub(crate) fn set_translation_mode(
+    state: &AppState,
+    device_id: &str,
+    args: TranslationModeArgs,
+) -> Option<String> {
+    if args.enabled {
+        let target = args
+            .target_language
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_TARGET_LANGUAGE.to_string());
+        state
+            .translations
+            .insert(device_id.to_string(), target.clone());
+        Some(target)
+    } else {
+        state.translations.remove(device_id);
+        None
+    }
+}
+
+/// Whisper only translates into English, other target languages need an extra step.
+pub(crate) fn is_english(language: &str) -> bool {
+    let language = language.trim();
+    language.eq_ignore_ascii_case(DEFAULT_TARGET_LANGUAGE) || language.eq_ignore_ascii_case("en")
+}
+
+/// Detect the voice command for leaving translation mode, from the English translation. Only
+/// a short imperative counts, e.g. "stop translating" or "turn the translation mode off", not
+/// a sentence that happens to mention both.
+pub(crate) fn is_stop_translation(text: &str) -> bool {
+    let text = text.to_lowercase();
+    let words: Vec<_> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if words.len() > MAX_STOP_WORDS {
+        return false;
+    }
+    let start = words.iter().take_while(|v| FILLERS.contains(v)).count();
+    match &words[start..] {
+        [verb, rest @ ..] if STOP_VERBS.contains(verb) => names_translation(rest),
+        ["turn" | "switch", "off", rest @ ..] => names_translation(rest),
+        ["turn" | "switch", rest @ ..] => rest
+            .iter()
+            .position(|v| *v == "off")
+            .is_some_and(|i| names_translation(&rest[..i])),
+        _ => false,
+    }
+}
+
+/// Whether the words right after the verb are the translation, e.g. "the translation mode".
+fn names_translation(words: &[&str]) -> bool {
+    let start = words
+        .iter()
+        .take_while(|v| ["the", "this", "your"].contains(v))
+        .count();
+    let words = &words[start..];
+    matches!(words.first(), Some(&("translation" | "translating"))) && words.len() <= 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stop_translation() {
+        assert!(is_stop_translation("Stop translating, please."));
+        assert!(is_stop_translation("Turn off the translation mode"));
+        assert!(!is_stop_translation("Where is the train station?"));
+        assert!(!is_stop_translation("Please translate this sentence"));
+        assert!(!is_stop_translation("Translate: I recommend you send it"));
+        assert!(is_stop_translation("OK, exit translation mode."));
+        assert!(is_stop_translation("Please turn the translation off"));
+        assert!(!is_stop_translation("The end of the translation was fine"));
+        assert!(!is_stop_translation("Translate: please don't stop"));
+        assert!(!is_stop_translation(
+            "I'll stop by after the translation class"
+        ));
+    }
+}
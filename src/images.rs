@@ -0,0 +1,138 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use image::{
+    imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbaImage,
+};
+use std::io::Cursor;
+
+// larger images are downscaled by the vision model anyway
+const MAX_UPLOAD_IMAGE_SIZE: u32 = 2048;
+const THUMBNAIL_SIZE: u32 = 512;
+// blurhash is computed on a tiny version of the image, which is good enough for a blur
+const BLURHASH_SOURCE_SIZE: u32 = 64;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const PLACEHOLDER_SIZE: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Thumbnail {
+    /// webp encoded thumbnail
+    pub(crate) data: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) blurhash: String,
+    /// data url of the blurhash, shown while the thumbnail is loading
+    pub(crate) placeholder: String,
+}
+
+/// Generate a downscaled webp thumbnail and the blurhash of the image, decoded once into the
+/// placeholder.
+pub(crate) fn thumbnail(data: &[u8]) -> Result<Thumbnail> {
+    let img = image::load_from_memory(data)?;
+    let thumb = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgba8();
+    let data = encode(thumb.clone(), ImageFormat::WebP)?;
+
+    let small = img
+        .thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE)
+        .to_rgba8();
+    let (x, y) = BLURHASH_COMPONENTS;
+    let blurhash = blurhash::encode(x, y, small.width(), small.height(), small.as_raw())?;
+    let placeholder = blurhash_data_url(&blurhash, thumb.width(), thumb.height())?;
+
+    Ok(Thumbnail {
+        data,
+        width: thumb.width(),
+        height: thumb.height(),
+        blurhash,
+        placeholder,
+    })
+}
+
+/// Decode the blurhash into a tiny png data url, used as the background while loading.
+fn blurhash_data_url(blurhash: &str, width: u32, height: u32) -> Result<String> {
+    let w = PLACEHOLDER_SIZE;
+    let h = (PLACEHOLDER_SIZE * height / width.max(1)).max(1);
+    let pixels = blurhash::decode(blurhash, w, h, 1.0)?;
+    let img = RgbaImage::from_raw(w, h, pixels)
+        .ok_or_else(|| anyhow::anyhow!("invalid blurhash pixels"))?;
+    let data = encode(img, ImageFormat::Png)?;
+    Ok(format!("data:image/png;base64,{}", STANDARD.encode(data)))
+}
+
+fn encode(img: RgbaImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(img).write_to(&mut buf, format)?;
+    Ok(buf.into_inner())
+}
+
+/// Convert an uploaded image (png or jpeg) into a png no larger than the vision model handles.
+/// Camera photos are stored sideways with an exif orientation, so it's applied before the
+/// png (which drops the exif) is written.
+pub(crate) fn normalize_upload(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    if img.width() > MAX_UPLOAD_IMAGE_SIZE || img.height() > MAX_UPLOAD_IMAGE_SIZE {
+        img = img.resize(
+            MAX_UPLOAD_IMAGE_SIZE,
+            MAX_UPLOAD_IMAGE_SIZE,
+            FilterType::Triangle,
+        );
+    }
+    let mut buf = Cursor::new(Vec::new());
+    img.write_to(&mut buf, ImageFormat::Png)?;
+    Ok(buf.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{codecs::jpeg::JpegEncoder, ImageEncoder, Rgb, RgbImage};
+
+    #[test]
+    fn test_thumbnail_should_be_webp_with_blurhash() {
+        let img = RgbImage::from_pixel(1792, 1024, Rgb([200, 100, 50]));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+
+        let ret = thumbnail(buf.get_ref()).unwrap();
+        assert_eq!(image::guess_format(&ret.data).unwrap(), ImageFormat::WebP);
+        assert_eq!((ret.width, ret.height), (512, 293));
+
+        assert!(ret.placeholder.starts_with("data:image/png;base64,"));
+    }
+
+    #[test]
+    fn test_normalize_upload_should_downscale_jpeg_into_png() {
+        let img = RgbImage::from_pixel(4096, 1024, Rgb([200, 100, 50]));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Jpeg).unwrap();
+
+        let data = normalize_upload(buf.get_ref()).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Png);
+        let ret = image::load_from_memory(&data).unwrap();
+        assert_eq!((ret.width(), ret.height()), (2048, 512));
+    }
+
+    #[test]
+    fn test_normalize_upload_should_apply_exif_orientation() {
+        let img = RgbImage::from_pixel(40, 20, Rgb([200, 100, 50]));
+        // big endian tiff header with a single ifd entry: Orientation (0x0112) = 6, i.e. rotate 90° cw
+        let exif = [
+            b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0,
+            0,
+        ];
+        let mut buf = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut buf);
+        encoder.set_exif_metadata(exif.to_vec()).unwrap();
+        encoder
+            .write_image(img.as_raw(), 40, 20, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let data = normalize_upload(&buf).unwrap();
+        let ret = image::load_from_memory(&data).unwrap();
+        assert_eq!((ret.width(), ret.height()), (20, 40));
+    }
+}
@@ -0,0 +1,416 @@
+use crate::{
+    documents::{chunks_to_jsonl, embed_file, load_chunks, DocumentChunk, DocumentKind},
+    knowledge_path, write_atomic, AppState,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use llm_sdk::LlmSdk;
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    sync::{mpsc, Mutex, RwLock},
+    time,
+};
+use tracing::{info, warn};
+
+// bigger files are most likely not notes
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+// saving a file or switching a branch sends a burst of events, index once it settles
+const DEBOUNCE: Duration = Duration::from_secs(2);
+// files that failed to index, e.g. the api being down, are tried again after a backoff
+const RETRY_MIN: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// A directory of notes and docs indexed for the search_documents tool, shared by all devices.
+#[derive(Debug)]
+pub(crate) struct KnowledgeBase {
+    root: PathBuf,
+    // where the index is kept
+    dir: PathBuf,
+    // serializes the syncs
+    lock: Mutex<()>,
+    // the loaded chunks, none until the first search after a sync
+    index: RwLock<Option<Arc<Vec<DocumentChunk>>>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    /// the directory indexed, the index is rebuilt if it changes
+    root: PathBuf,
+    files: Vec<IndexedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexedFile {
+    /// relative to the root, with forward slashes
+    path: String,
+    modified: DateTime<Utc>,
+    size: u64,
+}
+
+impl KnowledgeBase {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            dir: knowledge_path(),
+            lock: Mutex::new(()),
+            index: RwLock::new(None),
+        }
+    }
+
+    pub(crate) async fn index(&self) -> Result<Arc<Vec<DocumentChunk>>> {
+        if let Some(index) = self.index.read().await.as_ref() {
+            return Ok(index.clone());
+        }
+        let mut guard = self.index.write().await;
+        // loaded by another task while this one waited for the lock
+        if let Some(index) = guard.as_ref() {
+            return Ok(index.clone());
+        }
+        let index = Arc::new(load_chunks(&self.dir.join("chunks.jsonl")).await?);
+        *guard = Some(index.clone());
+        Ok(index)
+    }
+
+    /// Bring the index up to date with the directory, only the new and changed files are
+    /// embedded again. Returns how many files failed to index.
+    pub(crate) async fn sync(&self, llm: &LlmSdk) -> Result<usize> {
+        let _guard = self.lock.lock().await;
+        let root = self.root.clone();
+        let files = tokio::task::spawn_blocking(move || scan(&root)).await??;
+
+        let dir = &self.dir;
+        let manifest = load_manifest(dir).await?;
+        let previous = if manifest.root == self.root {
+            manifest.files
+        } else {
+            vec![]
+        };
+        let (unchanged, changed) = diff(&previous, files);
+        if unchanged.len() == previous.len() && changed.is_empty() {
+            return Ok(0);
+        }
+
+        let mut chunks: Vec<_> = if unchanged.is_empty() {
+            vec![]
+        } else {
+            let kept: Vec<_> = unchanged.iter().map(|v| v.path.as_str()).collect();
+            load_chunks(&dir.join("chunks.jsonl"))
+                .await?
+                .into_iter()
+                .filter(|v| v.path.as_deref().is_some_and(|p| kept.contains(&p)))
+                .collect()
+        };
+        let removed = previous.len() - unchanged.len();
+        let mut indexed = unchanged;
+        let mut failed = 0;
+        for file in changed {
+            match self.embed(llm, &file).await {
+                Ok(v) => {
+                    chunks.extend(v);
+                    indexed.push(file);
+                }
+                // left out of the manifest, so it's tried again on the next sync
+                Err(e) => {
+                    warn!("failed to index {}: {}", file.path, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if !dir.exists() {
+            fs::create_dir_all(dir).await?;
+        }
+        let manifest = Manifest {
+            root: self.root.clone(),
+            files: indexed,
+        };
+        write_atomic(&dir.join("chunks.jsonl"), &chunks_to_jsonl(&chunks)?).await?;
+        write_atomic(
+            &dir.join("manifest.json"),
+            &serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await?;
+        *self.index.write().await = None;
+        info!(
+            "knowledge base {} synced: {} files, {} chunks, {} removed or changed",
+            self.root.display(),
+            manifest.files.len(),
+            chunks.len(),
+            removed
+        );
+        Ok(failed)
+    }
+
+    async fn embed(&self, llm: &LlmSdk, file: &IndexedFile) -> Result<Vec<DocumentChunk>> {
+        let data = fs::read(self.root.join(&file.path)).await?;
+        let name = file.path.rsplit('/').next().unwrap_or(&file.path);
+        let chunks = embed_file(llm, name, data)
+            .await?
+            .into_iter()
+            .enumerate()
+            .map(|(index, (text, embedding))| DocumentChunk {
+                document_id: file.path.clone(),
+                document_name: name.to_string(),
+                path: Some(file.path.clone()),
+                index,
+                text,
+                embedding,
+            })
+            .collect();
+        Ok(chunks)
+    }
+}
+
+/// Index the configured knowledge base, then keep it up to date with the changes of its files.
+/// The files that failed to index are tried again with a backoff.
+pub async fn watch_knowledge_base(state: Arc<AppState>) {
+    let Some(knowledge) = &state.knowledge else {
+        return;
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) if !event.kind.is_access() => {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("knowledge base watch error: {}", e),
+        });
+    // watch before the first sync, so that nothing changed meanwhile is missed
+    let watcher = match watcher.and_then(|mut w| {
+        w.watch(&knowledge.root, RecursiveMode::Recursive)?;
+        Ok(w)
+    }) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            warn!(
+                "failed to watch {}, it's only indexed at startup: {}",
+                knowledge.root.display(),
+                e
+            );
+            None
+        }
+    };
+
+    let mut retry = RETRY_MIN;
+    loop {
+        let failed = match knowledge.sync(&state.llm).await {
+            Ok(0) => false,
+            Ok(n) => {
+                warn!(
+                    "{} knowledge base files not indexed, retry in {:?}",
+                    n, retry
+                );
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "failed to sync the knowledge base, retry in {:?}: {}",
+                    retry, e
+                );
+                true
+            }
+        };
+        if !failed {
+            retry = RETRY_MIN;
+            if rx.recv().await.is_none() {
+                return;
+            }
+        } else if watcher.is_some() {
+            // a change is synced right away, and so are the failed files along with it
+            if let Ok(None) = time::timeout(retry, rx.recv()).await {
+                return;
+            }
+            retry = (retry * 2).min(RETRY_MAX);
+        } else {
+            time::sleep(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+            continue;
+        }
+        while let Ok(Some(_)) = time::timeout(DEBOUNCE, rx.recv()).await {}
+    }
+}
+
+/// The supported files under the root, hidden files and directories skipped.
+fn scan(root: &Path) -> Result<Vec<IndexedFile>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // symlinks are skipped too, they may point outside or loop
+            let file_type = entry.file_type()?;
+            if name.starts_with('.') || file_type.is_symlink() {
+                continue;
+            }
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let meta = entry.metadata()?;
+            if DocumentKind::from_name(&name).is_none() || meta.len() > MAX_FILE_SIZE {
+                continue;
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(root)?.components();
+            files.push(IndexedFile {
+                path: relative
+                    .map(|v| v.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                modified: meta.modified()?.into(),
+                size: meta.len(),
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Split the files found into the ones indexed already and the ones to (re-)index.
+fn diff(previous: &[IndexedFile], files: Vec<IndexedFile>) -> (Vec<IndexedFile>, Vec<IndexedFile>) {
+    let previous: HashMap<_, _> = previous.iter().map(|v| (v.path.as_str(), v)).collect();
+    files
+        .into_iter()
+        .partition(|v| previous.get(v.path.as_str()) == Some(&v))
+}
+
+async fn load_manifest(dir: &Path) -> Result<Manifest> {
+    match fs::read(dir.join("manifest.json")).await {
+        Ok(data) => Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!("invalid knowledge base manifest: {}", e);
+            Manifest::default()
+        })),
+        Err(_) => Ok(Manifest::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spawn_fake;
+    use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_scan_should_find_changed_files() {
+        let root = std::env::temp_dir().join(format!("ava-knowledge-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("decisions")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join("decisions/deploy.md"), "No deploys on Fridays.").unwrap();
+        std::fs::write(root.join("notes.txt"), "Standup at 10.").unwrap();
+        std::fs::write(root.join("logo.png"), [0u8; 4]).unwrap();
+        std::fs::write(root.join(".git/HEAD"), "ref: main").unwrap();
+
+        let files = scan(&root).unwrap();
+        let paths: Vec<_> = files.iter().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths, vec!["decisions/deploy.md", "notes.txt"]);
+
+        let mut previous = files.clone();
+        previous[1].size += 1;
+        previous.push(IndexedFile {
+            path: "removed.md".to_string(),
+            ..files[0].clone()
+        });
+        let (unchanged, changed) = diff(&previous, files);
+        assert_eq!(unchanged.len(), 1);
+        assert_eq!(changed[0].path, "notes.txt");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_should_embed_changed_and_failed_files() {
+        // the texts embedded are kept, none while the api is down
+        let embedded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let down = Arc::new(AtomicBool::new(false));
+        let (received, is_down) = (embedded.clone(), down.clone());
+        let app = Router::new().route(
+            "/embeddings",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                if is_down.load(Ordering::SeqCst) {
+                    return (StatusCode::SERVICE_UNAVAILABLE, "try later").into_response();
+                }
+                let texts: Vec<String> = serde_json::from_value(body["input"].clone()).unwrap();
+                received.lock().unwrap().extend(texts.clone());
+                let data: Vec<_> = (0..texts.len())
+                    .map(|i| json!({ "object": "embedding", "index": i, "embedding": [1.0, 0.0] }))
+                    .collect();
+                Json(json!({
+                    "object": "list",
+                    "data": data,
+                    "model": "text-embedding-ada-002",
+                    "usage": { "prompt_tokens": 0, "total_tokens": 0 },
+                }))
+                .into_response()
+            }),
+        );
+        let base_url = spawn_fake(app);
+        let llm = LlmSdk::new(&base_url, "token", 0);
+
+        let tmp = std::env::temp_dir().join(format!("ava-knowledge-{}", uuid::Uuid::new_v4()));
+        let root = tmp.join("notes");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("deploy.md"), "No deploys on Fridays.").unwrap();
+        std::fs::write(root.join("standup.txt"), "Standup at 10.").unwrap();
+        let knowledge = KnowledgeBase {
+            dir: tmp.join("index"),
+            ..KnowledgeBase::new(&root)
+        };
+        let paths = |index: &[DocumentChunk]| {
+            let mut paths: Vec<_> = index.iter().filter_map(|v| v.path.clone()).collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(knowledge.sync(&llm).await.unwrap(), 0);
+        assert_eq!(embedded.lock().unwrap().len(), 2);
+        assert_eq!(
+            paths(&knowledge.index().await.unwrap()),
+            vec!["deploy.md", "standup.txt"]
+        );
+
+        // only the changed file is embedded, the removed one is dropped
+        embedded.lock().unwrap().clear();
+        std::fs::remove_file(root.join("deploy.md")).unwrap();
+        std::fs::write(root.join("standup.txt"), "Standup at 10:30.").unwrap();
+        assert_eq!(knowledge.sync(&llm).await.unwrap(), 0);
+        assert_eq!(*embedded.lock().unwrap(), vec!["Standup at 10:30."]);
+        assert_eq!(
+            paths(&knowledge.index().await.unwrap()),
+            vec!["standup.txt"]
+        );
+
+        down.store(true, Ordering::SeqCst);
+        std::fs::write(root.join("incident.md"), "The outage of Monday.").unwrap();
+        assert_eq!(knowledge.sync(&llm).await.unwrap(), 1);
+        assert_eq!(
+            paths(&knowledge.index().await.unwrap()),
+            vec!["standup.txt"]
+        );
+
+        // the failed file is tried again though nothing changed, then it's indexed
+        embedded.lock().unwrap().clear();
+        down.store(false, Ordering::SeqCst);
+        assert_eq!(knowledge.sync(&llm).await.unwrap(), 0);
+        assert_eq!(*embedded.lock().unwrap(), vec!["The outage of Monday."]);
+        assert_eq!(
+            paths(&knowledge.index().await.unwrap()),
+            vec!["incident.md", "standup.txt"]
+        );
+        assert_eq!(knowledge.sync(&llm).await.unwrap(), 0);
+        assert_eq!(embedded.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(tmp).unwrap();
+    }
+}
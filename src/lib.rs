@@ -1,18 +1,52 @@
+mod calculator;
+mod charts;
+mod code_files;
+mod conversation;
+mod documents;
 mod error;
 mod extractors;
+mod gallery;
 pub mod handlers;
+mod images;
+mod knowledge;
+mod markdown;
+mod memories;
+mod normalize;
+mod notion;
+mod openai;
+mod preferences;
+mod reminders;
+mod sandbox;
 mod tools;
+mod web;
 
 use std::{
+    collections::VecDeque,
     env,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use clap::Parser;
+use conversation::{parse_budget, ContextBudgets, Conversation};
 use dashmap::DashMap;
+use documents::DocumentChunk;
 use handlers::AssistantEvent;
+use knowledge::KnowledgeBase;
 use llm_sdk::LlmSdk;
-use tokio::sync::broadcast;
+use markdown::Highlighter;
+use notion::NotionClient;
+use openai::OpenAiClient;
+use preferences::Preferences;
+use reminders::Reminder;
+use tokio::sync::{broadcast, Mutex, Notify};
+use tools::GeneratedImage;
+
+pub use knowledge::watch_knowledge_base;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+// extra .sublime-syntax definitions for languages syntect doesn't ship with
+const SYNTAX_PATH: &str = "./syntaxes";
 
 #[derive(Debug, Parser)]
 #[clap(name = "ava")]
@@ -21,36 +55,110 @@ pub struct Args {
     pub port: u16,
     #[clap(short, long, default_value = "./.certs")]
     pub cert_path: String,
+    /// Directory of notes and docs to answer questions from, kept indexed as it changes
+    #[clap(short, long)]
+    pub knowledge_dir: Option<PathBuf>,
+    /// Max prompt tokens of a model as "model=tokens", e.g. "gpt-4-1106-preview=64000"
+    #[clap(long = "context-budget", value_parser = parse_budget)]
+    pub context_budgets: Vec<(String, usize)>,
 }
 
 #[derive(Debug)]
 pub struct AppState {
     pub(crate) llm: LlmSdk,
+    pub(crate) openai: OpenAiClient,
     // each device_id has a channel to send messages to
     pub(crate) events: DashMap<String, broadcast::Sender<AssistantEvent>>,
+    // each device_id in translation mode has a target language
+    pub(crate) translations: DashMap<String, String>,
+    // cached speech preferences of each device_id
+    pub(crate) preferences: DashMap<String, Preferences>,
+    // the recent turns and the summary of the older ones of each device_id
+    pub(crate) conversations: DashMap<String, Conversation>,
+    // how many prompt tokens each model is given
+    pub(crate) context_budgets: ContextBudgets,
+    // the recent replies with images of each device_id by reply id, oldest first, for edits to
+    // refer to
+    pub(crate) reply_images: DashMap<String, VecDeque<(String, Vec<GeneratedImage>)>>,
+    // serializes the updates of the gallery index files
+    pub(crate) gallery_lock: Mutex<()>,
+    // the pending reminders of all devices, loaded from their file on first use
+    pub(crate) reminders: Mutex<Option<Vec<Reminder>>>,
+    // wakes up the reminder scheduler when the reminders change or a device connects
+    pub(crate) reminders_changed: Notify,
+    // serializes the updates of the memory files
+    pub(crate) memories_lock: Mutex<()>,
+    // where the memory file of each device_id is kept
+    pub(crate) memories_dir: PathBuf,
+    // serializes the updates of the document index files
+    pub(crate) documents_lock: Mutex<()>,
+    // the loaded document chunks of each device_id, dropped when its documents change
+    pub(crate) document_indexes: DashMap<String, Arc<Vec<DocumentChunk>>>,
+    // shared by all the markdown rendering
+    pub(crate) highlighter: Highlighter,
+    // the to-do database, none if notion isn't configured
+    pub(crate) notion: Option<NotionClient>,
+    // the shared notes and docs, none if no directory is configured
+    pub(crate) knowledge: Option<KnowledgeBase>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
+        let token = env::var("OPENAI_API_KEY").unwrap();
+        Self {
+            notion: NotionClient::from_env(),
+            ..Self::with_openai(OPENAI_BASE_URL, &token, 3)
+        }
+    }
+}
+
+impl AppState {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            knowledge: args.knowledge_dir.as_ref().map(KnowledgeBase::new),
+            context_budgets: ContextBudgets::new(&args.context_budgets),
+            ..Default::default()
+        }
+    }
+
+    /// State calling the openai api at the base url without retries, and without notion.
+    #[cfg(test)]
+    pub(crate) fn for_test(base_url: &str, token: &str) -> Self {
+        Self::with_openai(base_url, token, 0)
+    }
+
+    fn with_openai(base_url: &str, token: &str, retries: u32) -> Self {
         Self {
-            llm: LlmSdk::new(
-                "https://api.openai.com/v1",
-                env::var("OPENAI_API_KEY").unwrap(),
-                3,
-            ),
+            llm: LlmSdk::new(base_url, token, retries),
+            openai: OpenAiClient::new(base_url, token),
             events: DashMap::new(),
+            translations: DashMap::new(),
+            preferences: DashMap::new(),
+            conversations: DashMap::new(),
+            context_budgets: ContextBudgets::default(),
+            reply_images: DashMap::new(),
+            gallery_lock: Mutex::new(()),
+            reminders: Mutex::new(None),
+            reminders_changed: Notify::new(),
+            memories_lock: Mutex::new(()),
+            memories_dir: memories_dir(),
+            documents_lock: Mutex::new(()),
+            document_indexes: DashMap::new(),
+            highlighter: Highlighter::new(SYNTAX_PATH),
+            notion: None,
+            knowledge: None,
         }
     }
 }
 
-pub fn audio_path(device_id: &str, name: &str) -> PathBuf {
+pub fn audio_path(device_id: &str, name: &str, ext: &str) -> PathBuf {
     Path::new("/tmp/ava-bot/audio")
         .join(device_id)
-        .join(format!("{}.mp3", name))
+        .join(format!("{}.{}", name, ext))
 }
 
-pub fn audio_url(device_id: &str, name: &str) -> String {
-    format!("/assets/audio/{}/{}.mp3", device_id, name)
+pub fn audio_url(device_id: &str, name: &str, ext: &str) -> String {
+    format!("/assets/audio/{}/{}.{}", device_id, name, ext)
 }
 
 pub fn image_path(device_id: &str, name: &str) -> PathBuf {
@@ -62,3 +170,93 @@ pub fn image_path(device_id: &str, name: &str) -> PathBuf {
 pub fn image_url(device_id: &str, name: &str) -> String {
     format!("/assets/image/{}/{}.png", device_id, name)
 }
+
+pub fn thumbnail_path(device_id: &str, name: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot/image")
+        .join(device_id)
+        .join(format!("{}.thumb.webp", name))
+}
+
+pub fn thumbnail_url(device_id: &str, name: &str) -> String {
+    format!("/assets/image/{}/{}.thumb.webp", device_id, name)
+}
+
+pub fn chart_path(device_id: &str, name: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot/chart")
+        .join(device_id)
+        .join(format!("{}.svg", name))
+}
+
+pub fn chart_url(device_id: &str, name: &str) -> String {
+    format!("/assets/chart/{}/{}.svg", device_id, name)
+}
+
+// kept out of /tmp/ava-bot as the model picks the file names, see `code_file_handler`
+pub fn code_root() -> PathBuf {
+    Path::new("/tmp/ava-bot-code").to_path_buf()
+}
+
+pub fn code_path(device_id: &str, id: &str) -> PathBuf {
+    code_root().join(device_id).join(id)
+}
+
+pub fn code_url(device_id: &str, id: &str, name: &str) -> String {
+    format!("/code/{}/{}/{}", device_id, id, name)
+}
+
+pub fn code_archive_path(device_id: &str, id: &str) -> PathBuf {
+    code_root().join(device_id).join(format!("{}.zip", id))
+}
+
+pub fn code_archive_url(device_id: &str, id: &str) -> String {
+    format!("/code/{}/{}.zip", device_id, id)
+}
+
+pub fn gallery_path(device_id: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot-data/gallery").join(format!("{}.jsonl", device_id))
+}
+
+pub fn documents_path(device_id: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot-data/documents").join(device_id)
+}
+
+pub fn knowledge_path() -> PathBuf {
+    Path::new("/tmp/ava-bot-data/knowledge").to_path_buf()
+}
+
+pub fn memories_dir() -> PathBuf {
+    Path::new("/tmp/ava-bot-data/memories").to_path_buf()
+}
+
+pub fn preferences_path(device_id: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot-data/preferences").join(format!("{}.json", device_id))
+}
+
+pub fn reminders_path() -> PathBuf {
+    Path::new("/tmp/ava-bot-data/reminders.json").to_path_buf()
+}
+
+pub fn sandbox_path(name: &str) -> PathBuf {
+    Path::new("/tmp/ava-bot-sandbox").join(name)
+}
+
+/// Write the file in a tmp file renamed over it, so that it's never left half written.
+pub(crate) async fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Serve the app on a free local port in the background, returns its base url.
+#[cfg(test)]
+pub(crate) fn spawn_fake(app: axum::Router) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    base_url
+}
@@ -1,10 +1,16 @@
 use anyhow::Result;
 use ava_bot::{
-    handlers::{assistant_handler, events_handler, index_page},
-    AppState, Args,
+    handlers::{
+        assistant_handler, code_file_handler, delete_document_handler,
+        delete_gallery_image_handler, delete_memory_handler, documents_page, events_handler,
+        gallery_page, get_settings_handler, get_translation_handler, index_page, memories_page,
+        reminder_scheduler, translation_handler, update_settings_handler, upload_document_handler,
+    },
+    watch_knowledge_base, AppState, Args,
 };
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
@@ -13,16 +19,46 @@ use std::sync::Arc;
 use tower_http::services::ServeDir;
 use tracing::info;
 
+// audio along with a photo from the camera
+const MAX_UPLOAD_SIZE: usize = 20 * 1024 * 1024;
+// pdf, markdown or text files to ask about
+const MAX_DOCUMENT_SIZE: usize = 20 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
-    let state = Arc::new(AppState::default());
+    let state = Arc::new(AppState::new(&args));
+    tokio::spawn(reminder_scheduler(state.clone()));
+    tokio::spawn(watch_knowledge_base(state.clone()));
     let app = Router::new()
         .route("/", get(index_page))
         .route("/events", get(events_handler))
-        .route("/assistant", post(assistant_handler))
+        .route(
+            "/assistant",
+            post(assistant_handler).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+        )
+        .route("/code/*path", get(code_file_handler))
+        .route("/gallery", get(gallery_page))
+        .route("/gallery/:id", delete(delete_gallery_image_handler))
+        .route(
+            "/documents",
+            get(documents_page)
+                .post(upload_document_handler)
+                .layer(DefaultBodyLimit::max(MAX_DOCUMENT_SIZE)),
+        )
+        .route("/documents/:id", delete(delete_document_handler))
+        .route("/memories", get(memories_page))
+        .route("/memories/:id", delete(delete_memory_handler))
+        .route(
+            "/translation",
+            get(get_translation_handler).post(translation_handler),
+        )
+        .route(
+            "/settings",
+            get(get_settings_handler).post(update_settings_handler),
+        )
         .nest_service("/public", ServeDir::new("./public"))
         .nest_service("/assets", ServeDir::new("/tmp/ava-bot"))
         .with_state(state);
@@ -0,0 +1,536 @@
+use crate::preferences::CodeTheme;
+use ammonia::Builder;
+use comrak::{
+    adapters::SyntaxHighlighterAdapter,
+    format_html_with_plugins,
+    html::{escape, write_opening_tag},
+    nodes::{AstNode, NodeHtmlBlock, NodeValue},
+    parse_document, Arena,
+};
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    path::Path,
+    sync::LazyLock,
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Color, Theme, ThemeSet},
+    html::{append_highlighted_html_for_styled_line, IncludeBackground},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+use tracing::{info, warn};
+
+// Allow-list for the HTML generated from model output: the inline styles and classes produced
+// by syntect highlighting, GFM tables, plain links and the MathML of the formulas. Everything
+// else is stripped.
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::default();
+    for tag in MATHML_TAGS {
+        builder.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+    }
+    builder
+        .add_tags(["input"])
+        .add_tags(MATHML_TAGS)
+        .add_tag_attributes("pre", ["style", "lang"])
+        .add_tag_attributes("span", ["style"])
+        .add_tag_attributes("code", ["class"])
+        .add_tag_attributes("th", ["align"])
+        .add_tag_attributes("td", ["align"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .set_tag_attribute_value("input", "disabled", "")
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .filter_style_properties(HashSet::from([
+            "color",
+            "background-color",
+            "font-weight",
+            "font-style",
+            "text-decoration",
+        ]));
+    builder
+});
+
+const MATHML_TAGS: &[&str] = &[
+    "math",
+    "semantics",
+    "mrow",
+    "mi",
+    "mn",
+    "mo",
+    "mtext",
+    "mspace",
+    "mstyle",
+    "mfrac",
+    "msqrt",
+    "mroot",
+    "msub",
+    "msup",
+    "msubsup",
+    "munder",
+    "mover",
+    "munderover",
+    "mmultiscripts",
+    "mprescripts",
+    "none",
+    "mtable",
+    "mtr",
+    "mtd",
+];
+
+const MATHML_ATTRIBUTES: &[&str] = &[
+    "display",
+    "displaystyle",
+    "mathvariant",
+    "accent",
+    "form",
+    "stretchy",
+    "minsize",
+    "maxsize",
+    "linethickness",
+    "width",
+    "columnalign",
+];
+
+/// Syntax and theme sets for code highlighting, loading them is expensive so it is done once.
+pub(crate) struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme_set: ThemeSet,
+}
+
+/// Highlights the code blocks with the theme, plugged into comrak when rendering markdown.
+pub(crate) struct ThemedHighlighter<'a> {
+    syntax_set: &'a SyntaxSet,
+    theme: &'a Theme,
+}
+
+impl Highlighter {
+    /// Load the default syntaxes along with the extra `.sublime-syntax` files in the folder.
+    pub(crate) fn new(extra_syntaxes: impl AsRef<Path>) -> Self {
+        let path = extra_syntaxes.as_ref();
+        let defaults = SyntaxSet::load_defaults_newlines();
+        let syntax_set = if path.is_dir() {
+            let mut builder = defaults.clone().into_builder();
+            match builder.add_from_folder(path, true) {
+                Ok(_) => {
+                    info!("loaded extra syntaxes from {}", path.display());
+                    builder.build()
+                }
+                Err(e) => {
+                    warn!("failed to load syntaxes from {}: {}", path.display(), e);
+                    defaults
+                }
+            }
+        } else {
+            defaults
+        };
+        Self {
+            syntax_set,
+            theme_set: ThemeSet::load_defaults(),
+        }
+    }
+
+    pub(crate) fn themed(&self, theme: CodeTheme) -> ThemedHighlighter<'_> {
+        ThemedHighlighter {
+            syntax_set: &self.syntax_set,
+            // the themes are from the default theme set, so they always exist
+            theme: &self.theme_set.themes[theme.name()],
+        }
+    }
+}
+
+impl fmt::Debug for Highlighter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Highlighter")
+            .field("syntaxes", &self.syntax_set.syntaxes().len())
+            .field("themes", &self.theme_set.themes.len())
+            .finish()
+    }
+}
+
+impl ThemedHighlighter<'_> {
+    fn background(&self) -> Color {
+        self.theme.settings.background.unwrap_or(Color::WHITE)
+    }
+
+    fn highlight(&self, lang: Option<&str>, code: &str) -> Result<String, syntect::Error> {
+        let syntax = lang
+            .filter(|v| !v.is_empty())
+            .and_then(|v| self.syntax_set.find_syntax_by_token(v))
+            .or_else(|| self.syntax_set.find_syntax_by_first_line(code))
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let mut highlighter = HighlightLines::new(syntax, self.theme);
+        let mut output = String::new();
+        for line in LinesWithEndings::from(code) {
+            let regions = highlighter.highlight_line(line, self.syntax_set)?;
+            append_highlighted_html_for_styled_line(
+                &regions,
+                IncludeBackground::IfDifferent(self.background()),
+                &mut output,
+            )?;
+        }
+        Ok(output)
+    }
+}
+
+impl SyntaxHighlighterAdapter for ThemedHighlighter<'_> {
+    fn write_highlighted(
+        &self,
+        output: &mut dyn Write,
+        lang: Option<&str>,
+        code: &str,
+    ) -> io::Result<()> {
+        match self.highlight(lang, code) {
+            Ok(html) => output.write_all(html.as_bytes()),
+            // comrak expects the code to be escaped by the adapter
+            Err(_) => escape(output, code.as_bytes()),
+        }
+    }
+
+    fn write_pre_tag(
+        &self,
+        output: &mut dyn Write,
+        mut attributes: HashMap<String, String>,
+    ) -> io::Result<()> {
+        let bg = self.background();
+        let style = format!("background-color:#{:02x}{:02x}{:02x};", bg.r, bg.g, bg.b);
+        attributes
+            .entry("style".to_string())
+            .and_modify(|v| v.insert_str(0, &style))
+            .or_insert(style);
+        write_opening_tag(output, "pre", attributes)
+    }
+
+    fn write_code_tag(
+        &self,
+        output: &mut dyn Write,
+        attributes: HashMap<String, String>,
+    ) -> io::Result<()> {
+        write_opening_tag(output, "code", attributes)
+    }
+}
+
+pub(crate) fn md2html(md: &str, highlighter: &ThemedHighlighter) -> String {
+    let mut options = comrak::Options::default();
+    options.extension.table = true;
+    options.extension.strikethrough = true;
+    options.extension.autolink = true;
+    options.extension.tasklist = true;
+    options.extension.math_dollars = true;
+    options.extension.math_code = true;
+    // only lets through the formulas rendered below, the raw html of the markdown is dropped
+    options.render.unsafe_ = true;
+    let mut plugins = comrak::Plugins::default();
+    plugins.render.codefence_syntax_highlighter = Some(highlighter);
+
+    let arena = Arena::new();
+    let root = parse_document(&arena, md, &options);
+    render_math(root);
+    let mut html = Vec::new();
+    if let Err(e) = format_html_with_plugins(root, &options, &mut html, &plugins) {
+        warn!("failed to render markdown: {}", e);
+    }
+    sanitize_html(&String::from_utf8_lossy(&html))
+}
+
+/// Replace the formulas with their MathML and drop the raw html, so that the only html nodes
+/// left are the formulas.
+fn render_math<'a>(root: &'a AstNode<'a>) {
+    let nodes: Vec<_> = root.descendants().collect();
+    for node in nodes {
+        let mut ast = node.data.borrow_mut();
+        ast.value = match &ast.value {
+            NodeValue::HtmlInline(_) | NodeValue::HtmlBlock(_) => {
+                drop(ast);
+                node.detach();
+                continue;
+            }
+            NodeValue::Math(math) => {
+                NodeValue::HtmlInline(latex2html(&math.literal, math.display_math))
+            }
+            NodeValue::CodeBlock(block) if block.info.trim() == "math" => {
+                NodeValue::HtmlBlock(NodeHtmlBlock {
+                    block_type: 0,
+                    literal: latex2html(&block.literal, true),
+                })
+            }
+            _ => continue,
+        };
+    }
+}
+
+fn latex2html(latex: &str, display: bool) -> String {
+    let style = if display {
+        DisplayStyle::Block
+    } else {
+        DisplayStyle::Inline
+    };
+    // some syntax errors are reported inline rather than as an error
+    match latex_to_mathml(latex.trim(), style) {
+        Ok(mathml) if !mathml.contains("[PARSE ERROR") => mathml,
+        // show the formula as is if it can't be converted
+        _ => {
+            let mut html = b"<code>".to_vec();
+            let _ = escape(&mut html, latex.trim().as_bytes());
+            html.extend_from_slice(b"</code>");
+            String::from_utf8_lossy(&html).into_owned()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CodeBlock {
+    /// first word of the fence info string, e.g. "python"
+    pub(crate) lang: String,
+    pub(crate) code: String,
+    /// filename mentioned in the fence info, the first line comment or the preceding paragraph
+    pub(crate) filename: Option<String>,
+}
+
+/// All the fenced or indented code blocks in the markdown, in order.
+pub(crate) fn extract_code_blocks(md: &str) -> Vec<CodeBlock> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, md, &comrak::Options::default());
+    root.descendants()
+        .filter_map(|node| match &node.data.borrow().value {
+            // math blocks are rendered as formulas rather than code
+            NodeValue::CodeBlock(block) if block.info.trim() != "math" => {
+                let mut info = block.info.split_whitespace();
+                let lang = info.next().unwrap_or_default().to_string();
+                let filename = info
+                    .find_map(filename_in_info)
+                    .or_else(|| filename_in_comment(&block.literal))
+                    .or_else(|| node.previous_sibling().and_then(filename_in_paragraph));
+                Some(CodeBlock {
+                    lang,
+                    code: block.literal.clone(),
+                    filename,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// e.g. ```rust src/main.rs or ```python title="app.py"
+fn filename_in_info(word: &str) -> Option<String> {
+    let word = word
+        .split_once('=')
+        .map(|(_, v)| v)
+        .unwrap_or(word)
+        .trim_matches(['"', '\'']);
+    is_filename(word).then(|| word.to_string())
+}
+
+// e.g. "// src/main.rs", "# file: app.py" or "<!-- index.html -->"
+fn filename_in_comment(code: &str) -> Option<String> {
+    let line = code.lines().next()?.trim();
+    let line = ["//", "#", "--", "/*", "<!--", ";"]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))?;
+    let line = line.trim_end_matches("*/").trim_end_matches("-->").trim();
+    let line = ["filename:", "file:"]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .unwrap_or(line)
+        .trim();
+    is_filename(line).then(|| line.to_string())
+}
+
+// e.g. "Create `src/main.rs`:" right before the code block
+fn filename_in_paragraph<'a>(node: &'a AstNode<'a>) -> Option<String> {
+    if !matches!(
+        node.data.borrow().value,
+        NodeValue::Paragraph | NodeValue::Heading(_)
+    ) {
+        return None;
+    }
+    node.descendants()
+        .filter_map(|child| match &child.data.borrow().value {
+            NodeValue::Code(code) if is_filename(&code.literal) => Some(code.literal.clone()),
+            _ => None,
+        })
+        .last()
+}
+
+fn is_filename(s: &str) -> bool {
+    if s.is_empty()
+        || s.len() > 100
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+    {
+        return false;
+    }
+    let name = s.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => {
+            !stem.is_empty()
+                && (1..=10).contains(&ext.len())
+                && ext.chars().all(|c| c.is_ascii_alphanumeric())
+                && !ext.chars().all(|c| c.is_ascii_digit())
+        }
+        None => matches!(name, "Dockerfile" | "Makefile" | "Justfile"),
+    }
+}
+
+/// Sanitize the html generated from untrusted content before it is rendered with `|safe`.
+pub(crate) fn sanitize_html(html: &str) -> String {
+    SANITIZER.clean(html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(md: &str) -> String {
+        let highlighter = Highlighter::new("");
+        md2html(md, &highlighter.themed(CodeTheme::SolarizedDark))
+    }
+
+    const XSS_PAYLOADS: &[&str] = &[
+        "<script>alert(1)</script>",
+        "<img src=x onerror=alert(1)>",
+        "<svg/onload=alert(1)>",
+        "<a href=\"javascript:alert(1)\">click</a>",
+        "<a href=\"JaVaScRiPt:alert(1)\">click</a>",
+        "<a href=\"data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==\">x</a>",
+        "<iframe src=\"https://evil.example\"></iframe>",
+        "<body onload=alert(1)>",
+        "<div style=\"background:url(javascript:alert(1))\">x</div>",
+        "<span style=\"position:fixed;top:0;left:0;width:100%;height:100%\">x</span>",
+        "<math><mtext><table><mglyph><style><img src=x onerror=alert(1)>",
+        "<form action=\"https://evil.example\"><button>go</button></form>",
+        "<object data=\"javascript:alert(1)\"></object>",
+        "<meta http-equiv=\"refresh\" content=\"0;url=https://evil.example\">",
+        "<details open ontoggle=alert(1)>",
+        "<input autofocus onfocus=alert(1)>",
+        "[x](javascript:alert(1))",
+        "![x](x\" onerror=\"alert(1))",
+        "<<script>script>alert(1)<</script>/script>",
+    ];
+
+    // MathML is allowed for the formulas, not what could smuggle html in it
+    const FORBIDDEN_TAGS: &[&str] = &[
+        "script", "iframe", "object", "form", "meta", "style", "svg", "mglyph", "body",
+    ];
+
+    const FORBIDDEN_ATTRS: &[&str] = &[
+        "onerror",
+        "onload",
+        "ontoggle",
+        "onfocus",
+        "javascript:",
+        "data:",
+        "position",
+        "url(",
+    ];
+
+    #[test]
+    fn test_sanitize_should_remove_xss_payloads() {
+        for payload in XSS_PAYLOADS {
+            for html in [sanitize_html(payload), render(payload)] {
+                // payloads rendered as escaped text are harmless, so only look into the tags
+                for tag in html.to_lowercase().split('<').skip(1) {
+                    let tag = tag.split('>').next().unwrap_or_default();
+                    let name = tag.split([' ', '/']).next().unwrap_or_default();
+                    assert!(
+                        !FORBIDDEN_TAGS.contains(&name),
+                        "<{}> found in {} (payload: {})",
+                        name,
+                        html,
+                        payload
+                    );
+                    for attr in FORBIDDEN_ATTRS {
+                        assert!(
+                            !tag.contains(attr),
+                            "{} found in {} (payload: {})",
+                            attr,
+                            html,
+                            payload
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sanitize_should_keep_highlighted_code_and_tables() {
+        let md = "```rust\nfn main() {}\n```\n\n| a | b |\n|:--|--:|\n| 1 | 2 |\n\n[docs](https://docs.rs)";
+        let html = render(md);
+        assert!(html.contains("<pre style=\"background-color:"));
+        assert!(html.contains("<span style=\"color:"));
+        assert!(html.contains("<td align=\"right\">2</td>"));
+        assert!(html.contains("<a href=\"https://docs.rs\" rel=\"noopener noreferrer nofollow\">"));
+    }
+
+    #[test]
+    fn test_extract_code_blocks_should_infer_filenames() {
+        let md = "Create `src/main.rs`:\n\n```rust\nfn main() {}\n```\n\n```toml title=\"Cargo.toml\"\n[package]\n```\n\n```python\n# file: app.py\nprint(1)\n```\n\nRun it with `python app.py`, e.g. version `3.11`:\n\n```\nls\n```\n";
+        let blocks = extract_code_blocks(md);
+        let names: Vec<_> = blocks.iter().map(|v| v.filename.as_deref()).collect();
+        assert_eq!(
+            names,
+            vec![
+                Some("src/main.rs"),
+                Some("Cargo.toml"),
+                Some("app.py"),
+                None
+            ]
+        );
+        assert_eq!(blocks[0].lang, "rust");
+        assert_eq!(blocks[0].code, "fn main() {}\n");
+        assert_eq!(blocks[3].lang, "");
+    }
+
+    #[test]
+    fn test_highlighter_should_load_extra_syntaxes() {
+        let md = "```toml\n[package]\nname = \"ava\"\n```\n";
+        let highlighter = Highlighter::new("");
+        assert!(highlighter
+            .syntax_set
+            .find_syntax_by_token("toml")
+            .is_none());
+
+        let highlighter = Highlighter::new("./syntaxes");
+        assert!(highlighter
+            .syntax_set
+            .find_syntax_by_token("toml")
+            .is_some());
+        let dark = md2html(md, &highlighter.themed(CodeTheme::SolarizedDark));
+        let light = md2html(md, &highlighter.themed(CodeTheme::InspiredGithub));
+        assert!(dark.contains("<pre style=\"background-color:#002b36\""));
+        assert!(light.contains("<pre style=\"background-color:#ffffff\""));
+        assert!(light.matches("<span style=\"color:").count() > 2);
+    }
+
+    #[test]
+    fn test_md2html_should_render_math() {
+        let md = "Euler: $e^{i\\pi} + 1 = 0$\n\n$$\n\\frac{a}{b}\n$$\n\n```math\nx^2\n```\n\nBad $\\text{<img src=x onerror=alert(1)>}$";
+        let html = render(md);
+        assert!(
+            html.contains("<math display=\"inline\"><msup><mi>e</mi>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<math display=\"block\"><mfrac>"), "{}", html);
+        assert!(
+            html.contains("<math display=\"block\"><msup><mi>x</mi><mn>2</mn>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<code>\\text{&lt;img src=x onerror=alert(1)&gt;}</code>"));
+        assert!(!html.contains("<img"), "{}", html);
+        // raw MathML in the markdown is dropped like any other raw html
+        let html = render("<math><mi>x</mi></math> and $y$");
+        assert_eq!(
+            html,
+            "<p>x and <math display=\"inline\"><mi>y</mi></math></p>\n"
+        );
+        assert!(extract_code_blocks(md).is_empty());
+    }
+}
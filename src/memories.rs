@@ -0,0 +1,275 @@
+use crate::{write_atomic, AppState};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::warn;
+use uuid::Uuid;
+
+const MAX_MEMORY_CHARS: usize = 500;
+// memories given to the model along with each request, the most relevant first
+const MAX_PROMPT_CHARS: usize = 2000;
+// words too common to tell what a memory is about
+const STOP_WORDS: &[&str] = &[
+    "about", "after", "all", "and", "any", "are", "but", "can", "could", "did", "does", "for",
+    "from", "had", "has", "have", "her", "him", "his", "how", "into", "its", "just", "like", "not",
+    "now", "off", "one", "our", "out", "she", "should", "some", "than", "that", "the", "their",
+    "them", "then", "there", "they", "this", "was", "were", "what", "when", "where", "which",
+    "who", "why", "will", "with", "would", "you", "your",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Memory {
+    pub(crate) id: String,
+    /// the fact in the user's words, e.g. "my daughter is allergic to peanuts"
+    pub(crate) content: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+impl Memory {
+    pub(crate) fn datetime(&self) -> String {
+        self.created_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    /// Whether the memory is about the query, i.e. the query is its id or a part of it. A longer
+    /// query only mentioning the memory doesn't match, so that forgetting stays narrow.
+    pub(crate) fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        !query.is_empty() && (self.id == query || self.content.to_lowercase().contains(&query))
+    }
+}
+
+impl AppState {
+    /// Remember the fact for the device, the same fact is only kept once.
+    pub(crate) async fn add_memory(&self, device_id: &str, content: &str) -> Result<Memory> {
+        let content = content.trim();
+        if content.is_empty() {
+            bail!("nothing to remember");
+        }
+        if content.chars().count() > MAX_MEMORY_CHARS {
+            bail!("that's too long to remember");
+        }
+        let path = self.memories_path(device_id);
+        let _guard = self.memories_lock.lock().await;
+        let mut memories = load_memories(&path).await?;
+        if let Some(memory) = memories
+            .iter()
+            .find(|v| v.content.eq_ignore_ascii_case(content))
+        {
+            return Ok(memory.clone());
+        }
+        let memory = Memory {
+            id: Uuid::new_v4().to_string(),
+            content: content.to_string(),
+            created_at: Utc::now(),
+        };
+        memories.push(memory.clone());
+        save_memories(&path, &memories).await?;
+        Ok(memory)
+    }
+
+    /// Memories of the device, newest first.
+    pub(crate) async fn memories(&self, device_id: &str) -> Result<Vec<Memory>> {
+        let _guard = self.memories_lock.lock().await;
+        let mut memories = load_memories(&self.memories_path(device_id)).await?;
+        memories.reverse();
+        Ok(memories)
+    }
+
+    /// Forget the memories matching the query, returns the ones forgotten.
+    pub(crate) async fn forget_memories(
+        &self,
+        device_id: &str,
+        query: &str,
+    ) -> Result<Vec<Memory>> {
+        let path = self.memories_path(device_id);
+        let _guard = self.memories_lock.lock().await;
+        let (forgotten, kept): (Vec<_>, Vec<_>) = load_memories(&path)
+            .await?
+            .into_iter()
+            .partition(|v| v.matches(query));
+        if !forgotten.is_empty() {
+            save_memories(&path, &kept).await?;
+        }
+        Ok(forgotten)
+    }
+
+    /// Remove the memory, returns false if not found.
+    pub(crate) async fn delete_memory(&self, device_id: &str, id: &str) -> Result<bool> {
+        let path = self.memories_path(device_id);
+        let _guard = self.memories_lock.lock().await;
+        let mut memories = load_memories(&path).await?;
+        let len = memories.len();
+        memories.retain(|v| v.id != id);
+        if memories.len() == len {
+            return Ok(false);
+        }
+        save_memories(&path, &memories).await?;
+        Ok(true)
+    }
+
+    fn memories_path(&self, device_id: &str) -> PathBuf {
+        self.memories_dir.join(format!("{}.json", device_id))
+    }
+
+    /// The memories relevant to the input, to append to a system prompt. Empty if there are none.
+    pub(crate) async fn memory_prompt(&self, device_id: &str, input: &str) -> Result<String> {
+        let memories = self.memories(device_id).await?;
+        let relevant = relevant_memories(&memories, input, MAX_PROMPT_CHARS);
+        if relevant.is_empty() {
+            return Ok(String::new());
+        }
+        let items: Vec<_> = relevant
+            .iter()
+            .map(|v| format!("- {}", v.content))
+            .collect();
+        Ok(format!(
+            "\n\nWhat you asked me to remember about you, use it when it helps:\n{}",
+            items.join("\n")
+        ))
+    }
+}
+
+/// The memories for the input within the size budget, the ones sharing the most words with it
+/// first, then the rest newest first. A memory too long for what's left is skipped, the shorter
+/// ones after it may fit.
+fn relevant_memories<'a>(memories: &'a [Memory], input: &str, max_chars: usize) -> Vec<&'a Memory> {
+    let input = words(input);
+    let mut ranked: Vec<_> = memories
+        .iter()
+        .map(|v| (words(&v.content).intersection(&input).count(), v))
+        .collect();
+    // stable, so the newest first among the same score
+    ranked.sort_by_key(|v| std::cmp::Reverse(v.0));
+
+    let mut chars = 0;
+    ranked
+        .into_iter()
+        .map(|(_, v)| v)
+        .filter(|v| {
+            let len = v.content.chars().count();
+            if chars + len > max_chars {
+                return false;
+            }
+            chars += len;
+            true
+        })
+        .collect()
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|v| v.chars().count() > 2)
+        .map(|v| v.to_lowercase())
+        .filter(|v| !STOP_WORDS.contains(&v.as_str()))
+        .collect()
+}
+
+async fn load_memories(path: &Path) -> Result<Vec<Memory>> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn!("invalid memories in {}: {}", path.display(), e);
+        vec![]
+    }))
+}
+
+async fn save_memories(path: &Path, memories: &[Memory]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent).await?;
+        }
+    }
+    write_atomic(path, &serde_json::to_vec_pretty(memories)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(content: &str) -> Memory {
+        Memory {
+            id: Uuid::new_v4().to_string(),
+            content: content.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_relevant_memories_should_fit_the_budget() {
+        // newest first, as returned by AppState::memories
+        let memories = vec![
+            memory("I write Rust at work and prefer examples with comments"),
+            memory("My daughter is allergic to peanuts"),
+            memory("I live in Seattle"),
+            memory("I like Rust"),
+        ];
+        // the ones sharing words come first, the rest still follow newest first
+        let relevant = relevant_memories(&memories, "Can my daughter eat peanut butter?", 1000);
+        let contents: Vec<_> = relevant.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "My daughter is allergic to peanuts",
+                "I write Rust at work and prefer examples with comments",
+                "I live in Seattle",
+                "I like Rust",
+            ]
+        );
+
+        // the first one doesn't fit, the next ones still do
+        let relevant = relevant_memories(&memories, "write a rust web server", 40);
+        let contents: Vec<_> = relevant.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(contents, vec!["I like Rust", "I live in Seattle"]);
+
+        // stop words don't make a memory look relevant
+        let relevant = relevant_memories(&memories, "what do you like about the weather", 80);
+        let contents: Vec<_> = relevant.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "I write Rust at work and prefer examples with comments",
+                "I live in Seattle"
+            ]
+        );
+
+        assert!(memories[1].matches("  allergic to PEANUTS "));
+        assert!(!memories[1].matches("cats"));
+
+        let tea = memory("I like tea");
+        assert!(tea.matches("i like tea"));
+        assert!(tea.matches(&tea.id));
+        assert!(!tea.matches("I like tea with my sister Ann"));
+        assert!(!tea.matches(" "));
+    }
+
+    #[tokio::test]
+    async fn test_memory_prompt_should_include_memories_without_shared_words() {
+        let dir = std::env::temp_dir().join(format!("ava-bot-test-{}", Uuid::new_v4()));
+        let state = AppState {
+            memories_dir: dir.clone(),
+            ..AppState::for_test("http://127.0.0.1:0", "token")
+        };
+        let device_id = "device";
+        state
+            .add_memory(device_id, "I prefer Rust examples")
+            .await
+            .unwrap();
+
+        let prompt = state
+            .memory_prompt(device_id, "write me a quicksort")
+            .await
+            .unwrap();
+        assert!(prompt.contains("- I prefer Rust examples"), "{}", prompt);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
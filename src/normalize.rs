@@ -0,0 +1,396 @@
+use comrak::{
+    arena_tree::Node,
+    nodes::{Ast, NodeValue},
+    parse_document, Arena, Options,
+};
+use std::{cell::RefCell, collections::BTreeMap};
+
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("dr.", "doctor"),
+    ("mr.", "mister"),
+    ("mrs.", "missus"),
+    ("prof.", "professor"),
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("etc.", "et cetera"),
+    ("vs.", "versus"),
+    ("vs", "versus"),
+    ("approx.", "approximately"),
+    ("&", "and"),
+    ("w/", "with"),
+    ("w/o", "without"),
+];
+
+// (symbol, singular, plural)
+const UNITS: &[(&str, &str, &str)] = &[
+    ("km/h", "kilometer per hour", "kilometers per hour"),
+    ("mph", "mile per hour", "miles per hour"),
+    ("km", "kilometer", "kilometers"),
+    ("cm", "centimeter", "centimeters"),
+    ("mm", "millimeter", "millimeters"),
+    ("m", "meter", "meters"),
+    ("mi", "mile", "miles"),
+    ("ft", "foot", "feet"),
+    ("kg", "kilogram", "kilograms"),
+    ("mg", "milligram", "milligrams"),
+    ("g", "gram", "grams"),
+    ("lb", "pound", "pounds"),
+    ("lbs", "pound", "pounds"),
+    ("ml", "milliliter", "milliliters"),
+    ("l", "liter", "liters"),
+    ("ms", "millisecond", "milliseconds"),
+    ("s", "second", "seconds"),
+    ("sec", "second", "seconds"),
+    ("min", "minute", "minutes"),
+    ("h", "hour", "hours"),
+    ("hr", "hour", "hours"),
+    ("hrs", "hour", "hours"),
+    ("kb", "kilobyte", "kilobytes"),
+    ("mb", "megabyte", "megabytes"),
+    ("gb", "gigabyte", "gigabytes"),
+    ("tb", "terabyte", "terabytes"),
+    ("hz", "hertz", "hertz"),
+    ("khz", "kilohertz", "kilohertz"),
+    ("mhz", "megahertz", "megahertz"),
+    ("ghz", "gigahertz", "gigahertz"),
+    ("w", "watt", "watts"),
+    ("kw", "kilowatt", "kilowatts"),
+    ("kwh", "kilowatt hour", "kilowatt hours"),
+    ("°c", "degree Celsius", "degrees Celsius"),
+    ("°f", "degree Fahrenheit", "degrees Fahrenheit"),
+    ("%", "percent", "percent"),
+];
+
+/// Turn the (markdown) text into something that sounds natural when read aloud. The text
+/// displayed to the user is not affected.
+pub(crate) fn speakable(text: &str, lexicon: &BTreeMap<String, String>) -> String {
+    let text = strip_markdown(text);
+    let mut out: Vec<String> = Vec::new();
+    // one sentence or paragraph per line, the last word of a line ends a sentence
+    for line in text.lines() {
+        let line = replace_phrases(line, lexicon);
+        let words: Vec<_> = line.split(' ').collect();
+        for (i, word) in words.iter().enumerate() {
+            let prev = if i > 0 { Some(words[i - 1]) } else { None };
+            out.push(expand_word(word, prev, i + 1 == words.len(), lexicon));
+        }
+    }
+    out.join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn strip_markdown(md: &str) -> String {
+    let arena = Arena::new();
+    let mut options = Options::default();
+    options.extension.table = true;
+    options.extension.strikethrough = true;
+    options.extension.autolink = true;
+    options.extension.tasklist = true;
+    let root = parse_document(&arena, md, &options);
+    let mut out = String::new();
+    collect_text(root, &mut out);
+    out
+}
+
+fn collect_text<'a>(node: &'a Node<'a, RefCell<Ast>>, out: &mut String) {
+    let value = node.data.borrow().value.clone();
+    match value {
+        NodeValue::Text(text) => out.push_str(&text),
+        NodeValue::Code(code) => out.push_str(&code.literal),
+        NodeValue::SoftBreak => out.push(' '),
+        NodeValue::LineBreak => end_sentence(out),
+        NodeValue::HtmlInline(_) | NodeValue::HtmlBlock(_) | NodeValue::ThematicBreak => {}
+        NodeValue::CodeBlock(block) => {
+            out.push_str(&describe_code_block(&block.info, &block.literal));
+            end_sentence(out);
+        }
+        NodeValue::Link(link) => {
+            let mut text = String::new();
+            for child in node.children() {
+                collect_text(child, &mut text);
+            }
+            let url = link.url.trim_start_matches("mailto:");
+            if text.is_empty() || text == link.url || text == url {
+                out.push_str(&describe_url(&link.url));
+            } else {
+                out.push_str(&text);
+            }
+        }
+        NodeValue::Image(_) => {
+            out.push_str("an image of ");
+            for child in node.children() {
+                collect_text(child, out);
+            }
+        }
+        NodeValue::TableCell => {
+            for child in node.children() {
+                collect_text(child, out);
+            }
+            out.push_str(", ");
+        }
+        NodeValue::Paragraph
+        | NodeValue::Heading(_)
+        | NodeValue::Item(_)
+        | NodeValue::TaskItem(_)
+        | NodeValue::TableRow(_) => {
+            for child in node.children() {
+                collect_text(child, out);
+            }
+            end_sentence(out);
+        }
+        _ => {
+            for child in node.children() {
+                collect_text(child, out);
+            }
+        }
+    }
+}
+
+fn end_sentence(out: &mut String) {
+    let trimmed = out.trim_end().trim_end_matches(',').trim_end().len();
+    out.truncate(trimmed);
+    if out.ends_with(|c: char| c.is_alphanumeric()) {
+        out.push('.');
+    }
+    out.push('\n');
+}
+
+fn describe_code_block(info: &str, literal: &str) -> String {
+    let lines = literal.lines().filter(|l| !l.trim().is_empty()).count();
+    let lang = info.split_whitespace().next().unwrap_or_default();
+    let lines = match lines {
+        1 => "one line".to_string(),
+        n => format!("{} lines", n),
+    };
+    if lang.is_empty() {
+        format!("Here's a code snippet with {}", lines)
+    } else {
+        format!("Here's a {} code snippet with {}", lang, lines)
+    }
+}
+
+fn describe_url(url: &str) -> String {
+    let host = url
+        .split("://")
+        .nth(1)
+        .unwrap_or(url)
+        .trim_start_matches("mailto:")
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("www.");
+    if host.is_empty() {
+        "a link".to_string()
+    } else {
+        format!("a link to {}", host)
+    }
+}
+
+fn expand_word(
+    word: &str,
+    prev: Option<&str>,
+    last: bool,
+    lexicon: &BTreeMap<String, String>,
+) -> String {
+    if word.is_empty() {
+        return String::new();
+    }
+    if word.starts_with("http://") || word.starts_with("https://") {
+        return describe_url(word);
+    }
+
+    // split trailing punctuation so that "5km.", "Ava," and "e.g.," still match
+    let end = word
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')'])
+        .len();
+    let (core, punct) = word.split_at(end);
+    if core.is_empty() {
+        return word.to_string();
+    }
+
+    // the user's lexicon first, so that it can override the built-in abbreviations
+    let found = lookup_lexicon(core, punct, lexicon)
+        .or_else(|| lookup_abbreviation(core, punct).map(|(v, punct)| (v.to_string(), punct)));
+    if let Some((v, rest)) = found {
+        // the dot of an abbreviation ending the sentence ends it as well
+        let period = if last && rest.is_empty() && !punct.is_empty() {
+            "."
+        } else {
+            ""
+        };
+        return format!("{}{}{}", v, rest, period);
+    }
+
+    // number with unit attached, e.g. "5km" or "20%"
+    let split = core
+        .char_indices()
+        .find(|(_, c)| !(c.is_ascii_digit() || *c == '.' || *c == ','))
+        .map(|(i, _)| i);
+    if let Some(i) = split.filter(|i| *i > 0) {
+        let (num, unit) = core.split_at(i);
+        if let Some(unit) = expand_unit(unit, num, true) {
+            return format!("{} {}{}", num, unit, punct);
+        }
+    }
+
+    // unit following a number, e.g. "5 km"
+    if let Some(prev) = prev.filter(|p| is_number(p)) {
+        if let Some(unit) = expand_unit(core, prev, false) {
+            return format!("{}{}", unit, punct);
+        }
+    }
+
+    word.to_string()
+}
+
+/// The expansion of the abbreviation and the punctuation left after it, the abbreviation's own
+/// dot being the first of the punctuation, e.g. "Dr" and ".,".
+fn lookup_abbreviation<'a>(core: &str, punct: &'a str) -> Option<(&'static str, &'a str)> {
+    let lower = core.to_lowercase();
+    let find = |key: &str| ABBREVIATIONS.iter().find(|(k, _)| *k == key).map(|v| v.1);
+    if let Some(rest) = punct.strip_prefix('.') {
+        if let Some(v) = find(&format!("{}.", lower)) {
+            return Some((v, rest));
+        }
+    }
+    find(&lower).map(|v| (v, punct))
+}
+
+/// Apply the lexicon entries of several words to the line, the single words are looked up one
+/// by one.
+fn replace_phrases(line: &str, lexicon: &BTreeMap<String, String>) -> String {
+    let mut line = line.to_string();
+    for (phrase, spoken) in lexicon.iter().filter(|(k, _)| k.contains(' ')) {
+        let phrase = phrase.to_ascii_lowercase();
+        let mut start = 0;
+        while let Some(i) = line[start..].to_ascii_lowercase().find(&phrase) {
+            let (begin, end) = (start + i, start + i + phrase.len());
+            let bounded = !line[..begin].ends_with(char::is_alphanumeric)
+                && !line[end..].starts_with(char::is_alphanumeric);
+            if bounded {
+                line.replace_range(begin..end, spoken);
+                start = begin + spoken.len();
+            } else {
+                start = end;
+            }
+        }
+    }
+    line
+}
+
+fn lookup_lexicon<'a>(
+    core: &str,
+    punct: &'a str,
+    lexicon: &BTreeMap<String, String>,
+) -> Option<(String, &'a str)> {
+    let find = |key: &str| {
+        lexicon
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone())
+    };
+    if let Some(rest) = punct.strip_prefix('.') {
+        if let Some(v) = find(&format!("{}.", core)) {
+            return Some((v, rest));
+        }
+    }
+    find(core).map(|v| (v, punct))
+}
+
+fn expand_unit(unit: &str, num: &str, attached: bool) -> Option<String> {
+    // single letters are only units when apart from the number and in lowercase, "1990s", "5G"
+    // and "3M" aren't
+    let letter = unit.chars().count() == 1 && unit.chars().all(char::is_alphabetic);
+    if letter && (attached || unit.chars().any(char::is_uppercase)) {
+        return None;
+    }
+    let unit = unit.to_lowercase();
+    UNITS.iter().find(|(k, _, _)| *k == unit).map(|(_, s, p)| {
+        if num == "1" {
+            s.to_string()
+        } else {
+            p.to_string()
+        }
+    })
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().any(|c| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == ',')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speakable_should_strip_markdown() {
+        let md = "# Title\n\nThis is **bold** and `code`, see [docs](https://docs.rs/comrak).\n\n- one\n- two\n";
+        let ret = speakable(md, &BTreeMap::new());
+        assert_eq!(ret, "Title. This is bold and code, see docs. one. two.");
+    }
+
+    #[test]
+    fn test_speakable_should_summarize_code_blocks() {
+        let md = "Try this:\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n";
+        let ret = speakable(md, &BTreeMap::new());
+        assert_eq!(ret, "Try this: Here's a rust code snippet with 3 lines.");
+    }
+
+    #[test]
+    fn test_speakable_should_expand_units_and_abbreviations() {
+        let ret = speakable(
+            "Run 5km, i.e. 3 mi at 20% effort & 1 h https://example.com/a",
+            &BTreeMap::new(),
+        );
+        assert_eq!(
+            ret,
+            "Run 5 kilometers, that is 3 miles at 20 percent effort and 1 hour a link to example.com."
+        );
+    }
+
+    #[test]
+    fn test_speakable_should_not_take_letters_after_numbers_for_units() {
+        let ret = speakable("Music of the 1990s, on 5G with 3M users", &BTreeMap::new());
+        assert_eq!(ret, "Music of the 1990s, on 5G with 3M users.");
+        let ret = speakable("Add 200 g of flour, 5 G networks", &BTreeMap::new());
+        assert_eq!(ret, "Add 200 grams of flour, 5 G networks.");
+    }
+
+    #[test]
+    fn test_speakable_should_apply_lexicon() {
+        let lexicon = BTreeMap::from([("nginx".to_string(), "engine x".to_string())]);
+        let ret = speakable("Restart Nginx, then retry.", &lexicon);
+        assert_eq!(ret, "Restart engine x, then retry.");
+
+        // the lexicon overrides the built-in abbreviations
+        let lexicon = BTreeMap::from([
+            ("Dr".to_string(), "Drive".to_string()),
+            ("vs.".to_string(), "against".to_string()),
+        ]);
+        let ret = speakable("Turn left on Elm Dr. Reds vs. Blues", &lexicon);
+        assert_eq!(ret, "Turn left on Elm Drive. Reds against Blues.");
+
+        let lexicon = BTreeMap::from([("New York".to_string(), "the big apple".to_string())]);
+        let ret = speakable("I love new york, and New Yorkers.", &lexicon);
+        assert_eq!(ret, "I love the big apple, and New Yorkers.");
+    }
+
+    #[test]
+    fn test_speakable_should_expand_abbreviations_before_punctuation() {
+        let ret = speakable(
+            "Call the Dr.\n\nFruits, e.g., apples, etc.) are fine",
+            &BTreeMap::new(),
+        );
+        assert_eq!(
+            ret,
+            "Call the doctor. Fruits, for example, apples, et cetera) are fine."
+        );
+        let ret = speakable("Dr. Smith said so, i.e. twice", &BTreeMap::new());
+        assert_eq!(ret, "doctor Smith said so, that is twice.");
+    }
+}
@@ -0,0 +1,368 @@
+use crate::tools::TaskPriority;
+use anyhow::{anyhow, Result};
+use reqwest::RequestBuilder;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{env, str::FromStr, time::Duration};
+use tracing::error;
+
+const NOTION_BASE_URL: &str = "https://api.notion.com/v1";
+const NOTION_VERSION: &str = "2022-06-28";
+const TIMEOUT: u64 = 30;
+// property names of the task database, see test.http
+const TITLE: &str = "Title";
+const FINISHED: &str = "Finished";
+const PRIORITY: &str = "Priority";
+
+/// Client for the to-do database in Notion.
+#[derive(Debug, Clone)]
+pub struct NotionClient {
+    base_url: String,
+    token: String,
+    database_id: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NotionTask {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) finished: bool,
+    pub(crate) priority: Option<TaskPriority>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryResponse {
+    results: Vec<Page>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Page {
+    id: String,
+    properties: PageProperties,
+}
+
+#[derive(Debug, Deserialize)]
+struct PageProperties {
+    #[serde(rename = "Title")]
+    title: TitleProperty,
+    #[serde(rename = "Finished", default)]
+    finished: Option<CheckboxProperty>,
+    #[serde(rename = "Priority", default)]
+    priority: Option<SelectProperty>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TitleProperty {
+    title: Vec<RichText>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RichText {
+    plain_text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckboxProperty {
+    checkbox: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelectProperty {
+    select: Option<SelectOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelectOption {
+    name: String,
+}
+
+impl NotionClient {
+    pub fn new(
+        base_url: impl Into<String>,
+        token: impl Into<String>,
+        database_id: impl Into<String>,
+    ) -> Self {
+        Self {
+            base_url: base_url.into(),
+            token: token.into(),
+            database_id: database_id.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Client configured by NOTION_API_KEY and NOTION_DATABASE_ID, none if either is missing.
+    pub fn from_env() -> Option<Self> {
+        let token = env::var("NOTION_API_KEY").ok()?;
+        let database_id = env::var("NOTION_DATABASE_ID").ok()?;
+        Some(Self::new(NOTION_BASE_URL, token, database_id))
+    }
+
+    pub(crate) async fn create_task(
+        &self,
+        title: &str,
+        priority: TaskPriority,
+    ) -> Result<NotionTask> {
+        let body = json!({
+            "parent": { "database_id": self.database_id },
+            "properties": {
+                TITLE: { "title": [{ "text": { "content": title } }] },
+                FINISHED: { "checkbox": false },
+                PRIORITY: { "select": { "name": priority.to_string() } },
+            },
+        });
+        let page: Page = send(self.request(reqwest::Method::POST, "pages").json(&body)).await?;
+        Ok(page.into())
+    }
+
+    /// Unfinished tasks, the most important first.
+    pub(crate) async fn open_tasks(&self) -> Result<Vec<NotionTask>> {
+        let body = json!({
+            "filter": { "property": FINISHED, "checkbox": { "equals": false } },
+            "sorts": [{ "timestamp": "created_time", "direction": "ascending" }],
+            "page_size": 100,
+        });
+        let path = format!("databases/{}/query", self.database_id);
+        let res: QueryResponse =
+            send(self.request(reqwest::Method::POST, &path).json(&body)).await?;
+        let mut tasks: Vec<NotionTask> = res.results.into_iter().map(Into::into).collect();
+        tasks.sort_by_key(|v| v.priority.map(|p| p.rank()).unwrap_or(u8::MAX));
+        Ok(tasks)
+    }
+
+    pub(crate) async fn finish_task(&self, id: &str) -> Result<NotionTask> {
+        let body = json!({ "properties": { FINISHED: { "checkbox": true } } });
+        let path = format!("pages/{}", id);
+        let page: Page = send(self.request(reqwest::Method::PATCH, &path).json(&body)).await?;
+        Ok(page.into())
+    }
+
+    /// The open tasks matching the spoken title, see `match_tasks`.
+    pub(crate) async fn find_open_tasks(&self, title: &str) -> Result<Vec<NotionTask>> {
+        Ok(match_tasks(self.open_tasks().await?, title))
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/{}", self.base_url, path);
+        self.client
+            .request(method, url)
+            .bearer_auth(&self.token)
+            .header("Notion-Version", NOTION_VERSION)
+            .timeout(Duration::from_secs(TIMEOUT))
+    }
+}
+
+impl From<Page> for NotionTask {
+    fn from(page: Page) -> Self {
+        let props = page.properties;
+        Self {
+            id: page.id,
+            title: props
+                .title
+                .title
+                .into_iter()
+                .map(|v| v.plain_text)
+                .collect(),
+            finished: props.finished.map(|v| v.checkbox).unwrap_or_default(),
+            priority: props
+                .priority
+                .and_then(|v| v.select)
+                .and_then(|v| TaskPriority::from_str(&v.name).ok()),
+        }
+    }
+}
+
+/// The tasks with the title if any, otherwise the ones containing it or contained in it as whole
+/// words, so that "it" doesn't match "submit report". Nothing matches an empty title.
+fn match_tasks(tasks: Vec<NotionTask>, title: &str) -> Vec<NotionTask> {
+    let title = words(title);
+    if title.is_empty() {
+        return vec![];
+    }
+    let (exact, rest): (Vec<_>, Vec<_>) = tasks.into_iter().partition(|v| words(&v.title) == title);
+    if !exact.is_empty() {
+        return exact;
+    }
+    rest.into_iter()
+        .filter(|v| {
+            let v = words(&v.title);
+            contains_words(&v, &title) || contains_words(&title, &v)
+        })
+        .collect()
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_lowercase())
+        .collect()
+}
+
+fn contains_words(words: &[String], part: &[String]) -> bool {
+    !part.is_empty() && words.windows(part.len()).any(|v| v == part)
+}
+
+async fn send<T: DeserializeOwned>(req: RequestBuilder) -> Result<T> {
+    let res = req.send().await?;
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let text = res.text().await?;
+        error!("Notion API failed: {}", text);
+        // notion explains the failure in the message field
+        let msg = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|v| v["message"].as_str().map(|v| v.to_string()))
+            .unwrap_or(text);
+        return Err(anyhow!("Notion API failed: {}", msg));
+    }
+    Ok(res.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spawn_fake;
+    use axum::{
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode},
+        routing::{patch, post},
+        Json, Router,
+    };
+    use std::sync::{Arc, Mutex};
+
+    type Pages = Arc<Mutex<Vec<Value>>>;
+
+    /// A fake of the few Notion endpoints we use, keeping the pages in memory.
+    fn fake_notion() -> String {
+        let pages = Pages::default();
+        let app = Router::new()
+            .route("/pages", post(create_page))
+            .route("/pages/:id", patch(update_page))
+            .route("/databases/:id/query", post(query_database))
+            .with_state(pages);
+        spawn_fake(app)
+    }
+
+    fn authorized(headers: &HeaderMap) -> Result<(), StatusCode> {
+        let auth = headers.get("authorization").and_then(|v| v.to_str().ok());
+        let version = headers.get("notion-version").and_then(|v| v.to_str().ok());
+        match (auth, version) {
+            (Some("Bearer secret"), Some(NOTION_VERSION)) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    async fn create_page(
+        State(pages): State<Pages>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Result<Json<Value>, StatusCode> {
+        authorized(&headers)?;
+        let mut pages = pages.lock().unwrap();
+        let mut props = body["properties"].clone();
+        props[TITLE]["title"][0]["plain_text"] =
+            props[TITLE]["title"][0]["text"]["content"].clone();
+        let page = json!({ "id": format!("page-{}", pages.len()), "properties": props });
+        pages.push(page.clone());
+        Ok(Json(page))
+    }
+
+    async fn update_page(
+        State(pages): State<Pages>,
+        Path(id): Path<String>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Result<Json<Value>, StatusCode> {
+        authorized(&headers)?;
+        let mut pages = pages.lock().unwrap();
+        let page = pages
+            .iter_mut()
+            .find(|v| v["id"] == id)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        page["properties"][FINISHED] = body["properties"][FINISHED].clone();
+        Ok(Json(page.clone()))
+    }
+
+    async fn query_database(
+        State(pages): State<Pages>,
+        Path(id): Path<String>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Result<Json<Value>, StatusCode> {
+        authorized(&headers)?;
+        if id != "tasks" {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let finished = &body["filter"]["checkbox"]["equals"];
+        let pages = pages.lock().unwrap();
+        let results: Vec<_> = pages
+            .iter()
+            .filter(|v| &v["properties"][FINISHED]["checkbox"] == finished)
+            .cloned()
+            .collect();
+        Ok(Json(json!({ "results": results })))
+    }
+
+    #[tokio::test]
+    async fn test_notion_client_should_manage_tasks() {
+        let base_url = fake_notion();
+        let client = NotionClient::new(&base_url, "secret", "tasks");
+
+        let task = client
+            .create_task("buy milk", TaskPriority::Low)
+            .await
+            .unwrap();
+        assert_eq!(task.title, "buy milk");
+        assert_eq!(task.priority, Some(TaskPriority::Low));
+        assert!(!task.finished);
+        client
+            .create_task("Pay rent", TaskPriority::High)
+            .await
+            .unwrap();
+
+        let tasks = client.open_tasks().await.unwrap();
+        let titles: Vec<_> = tasks.iter().map(|v| v.title.as_str()).collect();
+        assert_eq!(titles, vec!["Pay rent", "buy milk"]);
+
+        let tasks = client.find_open_tasks("the rent").await.unwrap();
+        assert!(tasks.is_empty());
+        let tasks = client.find_open_tasks("rent").await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        let task = client.finish_task(&tasks[0].id).await.unwrap();
+        assert!(task.finished);
+        assert_eq!(client.open_tasks().await.unwrap().len(), 1);
+
+        let client = NotionClient::new(&base_url, "wrong", "tasks");
+        assert!(client.open_tasks().await.is_err());
+    }
+
+    #[test]
+    fn test_match_tasks_should_only_match_whole_words() {
+        let task = |title: &str| NotionTask {
+            id: title.to_string(),
+            title: title.to_string(),
+            finished: false,
+            priority: None,
+        };
+        let tasks = vec![
+            task("Submit report"),
+            task("Submit report draft"),
+            task("Call mom"),
+        ];
+        let titles = |title: &str| -> Vec<String> {
+            match_tasks(tasks.clone(), title)
+                .into_iter()
+                .map(|v| v.title)
+                .collect()
+        };
+        assert!(titles("").is_empty());
+        assert!(titles("  ").is_empty());
+        assert!(titles("it").is_empty());
+        assert_eq!(titles("submit REPORT"), vec!["Submit report"]);
+        assert_eq!(
+            titles("report"),
+            vec!["Submit report", "Submit report draft"]
+        );
+        assert_eq!(titles("call mom tonight"), vec!["Call mom"]);
+    }
+}
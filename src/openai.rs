@@ -0,0 +1,409 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use image::{imageops, imageops::FilterType, ImageFormat, Rgba, RgbaImage};
+use reqwest::{
+    multipart::{Form, Part},
+    RequestBuilder,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use std::{io::Cursor, time::Duration};
+use tracing::{error, warn};
+
+const TIMEOUT: u64 = 60;
+// dall-e-2 only accepts square png images smaller than 4MB
+const EDIT_IMAGE_SIZE: u32 = 1024;
+const MAX_EDIT_IMAGE_BYTES: usize = 4 * 1024 * 1024;
+const VISION_MODEL: &str = "gpt-4-1106-vision-preview";
+// vision preview defaults to a very small max_tokens
+const VISION_MAX_TOKENS: usize = 1024;
+
+/// Client for the OpenAI endpoints not covered by llm-sdk yet.
+#[derive(Debug, Clone)]
+pub struct OpenAiClient {
+    base_url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ImageEditResponse {
+    data: Vec<ImageEditData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ImageEditData {
+    b64_json: String,
+}
+
+/// The part of an image to edit, in fractions of its size from the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct EditRegion {
+    pub(crate) x: f32,
+    pub(crate) y: f32,
+    pub(crate) width: f32,
+    pub(crate) height: f32,
+}
+
+impl EditRegion {
+    const FULL: Self = Self {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 1.0,
+    };
+}
+
+/// Where the image sits in the square sent to the edit endpoints, in pixels. The rest is
+/// padded, so that nothing of a wide or tall image is cut off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Letterbox {
+    size: u32,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VisionResponse {
+    choices: Vec<VisionChoice>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VisionChoice {
+    message: VisionMessage,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VisionMessage {
+    content: Option<String>,
+}
+
+impl OpenAiClient {
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            token: token.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Edit the image following the instruction, only the part the instruction is about is
+    /// changed, or all of it if that part can't be located. The prompt describes the whole image
+    /// after the edit. Returns the edited png images, in the shape of the original.
+    pub(crate) async fn edit_image(
+        &self,
+        image: &[u8],
+        instruction: &str,
+        prompt: &str,
+        n: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (image, frame) = prepare_image(image)?;
+        let region = match self.locate_edit(&image, instruction).await {
+            Ok(region) => region,
+            Err(e) => {
+                warn!("failed to locate the edit, editing the whole image: {}", e);
+                EditRegion::FULL
+            }
+        };
+        let mask = edit_mask(frame, region).or_else(|_| edit_mask(frame, EditRegion::FULL))?;
+        let form = Form::new()
+            .part("image", png_part(image, "image.png")?)
+            .part("mask", png_part(mask, "mask.png")?)
+            .text("prompt", truncate(prompt, 1000).to_string())
+            .text("n", n.to_string())
+            .text("size", format!("{}x{}", frame.size, frame.size))
+            .text("response_format", "b64_json");
+        let res = self.post_form("images/edits", form).await?;
+        unbox_images(res, frame)
+    }
+
+    /// Create variations of the image, returns the png images in the shape of the original.
+    pub(crate) async fn create_image_variation(
+        &self,
+        image: &[u8],
+        n: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (image, frame) = prepare_image(image)?;
+        let form = Form::new()
+            .part("image", png_part(image, "image.png")?)
+            .text("n", n.to_string())
+            .text("size", format!("{}x{}", frame.size, frame.size))
+            .text("response_format", "b64_json");
+        let res = self.post_form("images/variations", form).await?;
+        unbox_images(res, frame)
+    }
+
+    /// Ask the vision model which part of the png image the instruction changes.
+    async fn locate_edit(&self, png: &[u8], instruction: &str) -> Result<EditRegion> {
+        let prompt = format!(
+            "Which part of the image has to change for this edit: \"{}\"? Reply with only a JSON object like {{\"x\": 0.1, \"y\": 0.5, \"width\": 0.4, \"height\": 0.3}}, in fractions of the image size from the top left. Use the smallest box covering everything that changes, the whole image only if the edit changes all of it.",
+            truncate(instruction, 1000)
+        );
+        let content = self
+            .vision_completion(
+                "I locate the part of an image that an edit applies to.",
+                &prompt,
+                png,
+            )
+            .await?;
+        let json = match (content.find('{'), content.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &content[start..=end],
+            _ => return Err(anyhow!("no region found for the edit: {}", content)),
+        };
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Ask the vision model about the png image.
+    pub(crate) async fn vision_completion(
+        &self,
+        system: &str,
+        prompt: &str,
+        png: &[u8],
+    ) -> Result<String> {
+        let image_url = format!("data:image/png;base64,{}", STANDARD.encode(png));
+        let body = json!({
+            "model": VISION_MODEL,
+            "max_tokens": VISION_MAX_TOKENS,
+            "messages": [
+                { "role": "system", "content": system },
+                {
+                    "role": "user",
+                    "content": [
+                        { "type": "text", "text": prompt },
+                        { "type": "image_url", "image_url": { "url": image_url } },
+                    ],
+                },
+            ],
+        });
+        let req = self.post("chat/completions").json(&body);
+        let mut res: VisionResponse = send(req).await?;
+        res.choices
+            .pop()
+            .and_then(|v| v.message.content)
+            .ok_or_else(|| anyhow!("expect content but no content available"))
+    }
+
+    async fn post_form(&self, path: &str, form: Form) -> Result<ImageEditResponse> {
+        send(self.post(path).multipart(form)).await
+    }
+
+    fn post(&self, path: &str) -> RequestBuilder {
+        let url = format!("{}/{}", self.base_url, path);
+        self.client
+            .post(url)
+            .bearer_auth(&self.token)
+            .timeout(Duration::from_secs(TIMEOUT))
+    }
+}
+
+async fn send<T: DeserializeOwned>(req: RequestBuilder) -> Result<T> {
+    let res = req.send().await?;
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let text = res.text().await?;
+        error!("API failed: {}", text);
+        return Err(anyhow!("API failed: {}", text));
+    }
+    Ok(res.json().await?)
+}
+
+/// Letterbox the image into a square opaque RGBA png that the edit endpoints accept.
+fn prepare_image(data: &[u8]) -> Result<(Vec<u8>, Letterbox)> {
+    let img = image::load_from_memory(data)?;
+    for size in [EDIT_IMAGE_SIZE, EDIT_IMAGE_SIZE / 2, EDIT_IMAGE_SIZE / 4] {
+        let resized = img.resize(size, size, FilterType::Lanczos3).to_rgba8();
+        let frame = Letterbox {
+            size,
+            x: (size - resized.width()) / 2,
+            y: (size - resized.height()) / 2,
+            width: resized.width(),
+            height: resized.height(),
+        };
+        let mut square = RgbaImage::from_pixel(size, size, Rgba([0, 0, 0, 255]));
+        imageops::overlay(&mut square, &resized, frame.x as i64, frame.y as i64);
+        let buf = encode_png(&square)?;
+        if buf.len() < MAX_EDIT_IMAGE_BYTES {
+            return Ok((buf, frame));
+        }
+    }
+    Err(anyhow!("image is too large to edit"))
+}
+
+/// Crop the padding of `prepare_image` off the returned images.
+fn unbox_images(res: ImageEditResponse, frame: Letterbox) -> Result<Vec<Vec<u8>>> {
+    res.data
+        .into_iter()
+        .map(|v| {
+            let img = image::load_from_memory(&STANDARD.decode(v.b64_json)?)?;
+            // in case the image doesn't come back in the size asked for
+            let scale = |v: u32| v * img.width() / frame.size;
+            let img = img
+                .crop_imm(
+                    scale(frame.x),
+                    scale(frame.y),
+                    scale(frame.width),
+                    scale(frame.height),
+                )
+                .to_rgba8();
+            encode_png(&img)
+        })
+        .collect()
+}
+
+/// Opaque but for the region within the image, the edit endpoint only repaints the transparent
+/// pixels. The region is in fractions of the whole square, padding included.
+fn edit_mask(frame: Letterbox, region: EditRegion) -> Result<Vec<u8>> {
+    let size = frame.size;
+    let pixels = |v: f32| (v.clamp(0.0, 1.0) * size as f32).round() as u32;
+    let left = pixels(region.x).max(frame.x);
+    let top = pixels(region.y).max(frame.y);
+    let right = pixels(region.x + region.width).min(frame.x + frame.width);
+    let bottom = pixels(region.y + region.height).min(frame.y + frame.height);
+    if right <= left || bottom <= top {
+        return Err(anyhow!("empty region to edit: {:?}", region));
+    }
+    let mask = RgbaImage::from_fn(size, size, |x, y| {
+        if (left..right).contains(&x) && (top..bottom).contains(&y) {
+            Rgba([0, 0, 0, 0])
+        } else {
+            Rgba([0, 0, 0, 255])
+        }
+    });
+    encode_png(&mask)
+}
+
+fn encode_png(img: &RgbaImage) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    img.write_to(&mut buf, ImageFormat::Png)?;
+    Ok(buf.into_inner())
+}
+
+fn png_part(data: Vec<u8>, name: &'static str) -> Result<Part> {
+    Ok(Part::bytes(data).file_name(name).mime_str("image/png")?)
+}
+
+fn truncate(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spawn_fake;
+    use axum::{extract::Multipart, routing::post, Json, Router};
+    use image::{Rgb, RgbImage};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn test_prepare_image_should_letterbox_to_square_rgba() {
+        let img = RgbImage::from_pixel(300, 200, Rgb([10, 20, 30]));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+
+        let (data, frame) = prepare_image(buf.get_ref()).unwrap();
+        assert_eq!(
+            frame,
+            Letterbox {
+                size: EDIT_IMAGE_SIZE,
+                x: 0,
+                y: 170,
+                width: 1024,
+                height: 683,
+            }
+        );
+        let ret = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert_eq!((ret.width(), ret.height()), (1024, 1024));
+        // nothing is cut off, the padding is opaque so that it's never repainted
+        assert_eq!(*ret.get_pixel(512, 10), Rgba([0, 0, 0, 255]));
+        assert_eq!(*ret.get_pixel(512, 512), Rgba([10, 20, 30, 255]));
+    }
+
+    #[tokio::test]
+    async fn test_edit_image_should_only_repaint_the_located_region() {
+        let content = "```json\n{\"x\": 0.5, \"y\": 0.25, \"width\": 0.5, \"height\": 0.25}\n```";
+        let (fields, images) = edit(content).await;
+        let text = |name: &str| String::from_utf8(fields[name].clone()).unwrap();
+        assert_eq!(text("prompt"), "a red sun over the sea");
+        assert_eq!(text("n"), "2");
+        assert_eq!(text("size"), "1024x1024");
+        assert_eq!(text("response_format"), "b64_json");
+        let image = image::load_from_memory(&fields["image"]).unwrap();
+        assert_eq!((image.width(), image.height()), (1024, 1024));
+        // only the top right quarter of the wide image is transparent
+        let mask = image::load_from_memory(&fields["mask"]).unwrap().to_rgba8();
+        assert_eq!((mask.width(), mask.height()), (1024, 1024));
+        assert_eq!(mask.get_pixel(1000, 256)[3], 0);
+        assert_eq!(mask.get_pixel(512, 511)[3], 0);
+        assert_eq!(mask.get_pixel(511, 300)[3], 255);
+        assert_eq!(mask.get_pixel(1000, 512)[3], 255);
+        assert_eq!(mask.get_pixel(1000, 10)[3], 255);
+
+        // the padding is cropped off the edited images
+        assert_eq!(images.len(), 1);
+        let image = image::load_from_memory(&images[0]).unwrap();
+        assert_eq!((image.width(), image.height()), (1024, 512));
+    }
+
+    #[tokio::test]
+    async fn test_edit_image_should_repaint_all_of_it_without_a_region() {
+        let (fields, images) = edit("I can't tell").await;
+        let mask = image::load_from_memory(&fields["mask"]).unwrap().to_rgba8();
+        assert_eq!(mask.get_pixel(0, 256)[3], 0);
+        assert_eq!(mask.get_pixel(1023, 767)[3], 0);
+        assert_eq!(mask.get_pixel(0, 255)[3], 255);
+        assert_eq!(mask.get_pixel(1023, 768)[3], 255);
+        assert_eq!(images.len(), 1);
+    }
+
+    /// Edit a 2:1 image with the vision model replying the content, returns the fields sent to
+    /// the edit endpoint and the edited images.
+    async fn edit(content: &'static str) -> (HashMap<String, Vec<u8>>, Vec<Vec<u8>>) {
+        let fields = Arc::new(Mutex::new(HashMap::new()));
+        let received = fields.clone();
+        let edited = RgbaImage::from_pixel(1024, 1024, Rgba([200, 0, 0, 255]));
+        let edited = STANDARD.encode(encode_png(&edited).unwrap());
+        let app = Router::new()
+            .route(
+                "/chat/completions",
+                post(move || async move {
+                    Json(json!({ "choices": [{ "message": { "content": content } }] }))
+                }),
+            )
+            .route(
+                "/images/edits",
+                post(|mut form: Multipart| async move {
+                    while let Some(field) = form.next_field().await.unwrap() {
+                        let name = field.name().unwrap().to_string();
+                        let data = field.bytes().await.unwrap().to_vec();
+                        received.lock().unwrap().insert(name, data);
+                    }
+                    Json(json!({ "data": [{ "b64_json": edited }] }))
+                }),
+            );
+        let base_url = spawn_fake(app);
+
+        let img = RgbImage::from_pixel(128, 64, Rgb([10, 20, 30]));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+        let client = OpenAiClient::new(base_url, "token");
+        let images = client
+            .edit_image(
+                buf.get_ref(),
+                "make the sun red",
+                "a red sun over the sea",
+                2,
+            )
+            .await
+            .unwrap();
+        let fields = fields.lock().unwrap().clone();
+        (fields, images)
+    }
+}
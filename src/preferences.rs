@@ -0,0 +1,265 @@
+use crate::{preferences_path, write_atomic, AppState};
+use llm_sdk::{SpeechModel, SpeechResponseFormat, SpeechVoice};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use strum::{Display, EnumString};
+use tokio::fs;
+use tracing::warn;
+
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 4.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+    /// voice used for speech synthesis
+    pub voice: Voice,
+    /// speech speed, from 0.25 to 4.0
+    pub speed: f32,
+    /// speech model quality
+    pub quality: SpeechQuality,
+    /// output format of the generated audio
+    pub format: AudioFormat,
+    /// pronunciation lexicon, maps a word to how it shall be spoken
+    pub lexicon: BTreeMap<String, String>,
+    /// code highlighting theme when the device is in light mode
+    pub light_code_theme: CodeTheme,
+    /// code highlighting theme when the device is in dark mode
+    pub dark_code_theme: CodeTheme,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Voice {
+    Alloy,
+    Echo,
+    Fable,
+    Onyx,
+    #[default]
+    Nova,
+    Shimmer,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SpeechQuality {
+    #[default]
+    Standard,
+    Hd,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AudioFormat {
+    #[default]
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+}
+
+/// The default syntect themes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum CodeTheme {
+    InspiredGithub,
+    SolarizedLight,
+    SolarizedDark,
+    Base16OceanLight,
+    Base16OceanDark,
+    Base16EightiesDark,
+    Base16MochaDark,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ColorScheme {
+    #[default]
+    Light,
+    Dark,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            voice: Voice::default(),
+            speed: 1.0,
+            quality: SpeechQuality::default(),
+            format: AudioFormat::default(),
+            lexicon: BTreeMap::new(),
+            light_code_theme: CodeTheme::InspiredGithub,
+            dark_code_theme: CodeTheme::SolarizedDark,
+        }
+    }
+}
+
+impl Preferences {
+    /// Clamp the values into the range supported by the speech API.
+    pub fn normalize(mut self) -> Self {
+        self.speed = if self.speed.is_finite() {
+            self.speed.clamp(MIN_SPEED, MAX_SPEED)
+        } else {
+            1.0
+        };
+        self.lexicon = self
+            .lexicon
+            .into_iter()
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+            .collect();
+        self
+    }
+
+    /// The code theme for the color scheme the device is currently in.
+    pub fn code_theme(&self, scheme: ColorScheme) -> CodeTheme {
+        match scheme {
+            ColorScheme::Light => self.light_code_theme,
+            ColorScheme::Dark => self.dark_code_theme,
+        }
+    }
+}
+
+impl CodeTheme {
+    /// name of the theme in the syntect theme set
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodeTheme::InspiredGithub => "InspiredGitHub",
+            CodeTheme::SolarizedLight => "Solarized (light)",
+            CodeTheme::SolarizedDark => "Solarized (dark)",
+            CodeTheme::Base16OceanLight => "base16-ocean.light",
+            CodeTheme::Base16OceanDark => "base16-ocean.dark",
+            CodeTheme::Base16EightiesDark => "base16-eighties.dark",
+            CodeTheme::Base16MochaDark => "base16-mocha.dark",
+        }
+    }
+}
+
+impl AudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Aac => "aac",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "audio/mpeg",
+            // the speech API wraps opus in an ogg container
+            AudioFormat::Opus => "audio/ogg",
+            AudioFormat::Aac => "audio/aac",
+            AudioFormat::Flac => "audio/flac",
+        }
+    }
+}
+
+impl From<Voice> for SpeechVoice {
+    fn from(voice: Voice) -> Self {
+        match voice {
+            Voice::Alloy => SpeechVoice::Alloy,
+            Voice::Echo => SpeechVoice::Echo,
+            Voice::Fable => SpeechVoice::Fable,
+            Voice::Onyx => SpeechVoice::Onyx,
+            Voice::Nova => SpeechVoice::Nova,
+            Voice::Shimmer => SpeechVoice::Shimmer,
+        }
+    }
+}
+
+impl From<SpeechQuality> for SpeechModel {
+    fn from(quality: SpeechQuality) -> Self {
+        match quality {
+            SpeechQuality::Standard => SpeechModel::Tts1,
+            SpeechQuality::Hd => SpeechModel::Tts1Hd,
+        }
+    }
+}
+
+impl From<AudioFormat> for SpeechResponseFormat {
+    fn from(format: AudioFormat) -> Self {
+        match format {
+            AudioFormat::Mp3 => SpeechResponseFormat::Mp3,
+            AudioFormat::Opus => SpeechResponseFormat::Opus,
+            AudioFormat::Aac => SpeechResponseFormat::Aac,
+            AudioFormat::Flac => SpeechResponseFormat::Flac,
+        }
+    }
+}
+
+impl AppState {
+    /// Get the preferences of the device, loading them from disk on first access.
+    pub(crate) async fn preferences(&self, device_id: &str) -> Preferences {
+        if let Some(prefs) = self.preferences.get(device_id) {
+            return prefs.clone();
+        }
+
+        let prefs = match fs::read(preferences_path(device_id)).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("invalid preferences for {}: {}", device_id, e);
+                Preferences::default()
+            }),
+            Err(_) => Preferences::default(),
+        };
+        self.preferences
+            .insert(device_id.to_string(), prefs.clone());
+        prefs
+    }
+
+    pub(crate) async fn save_preferences(
+        &self,
+        device_id: &str,
+        prefs: Preferences,
+    ) -> anyhow::Result<Preferences> {
+        let prefs = prefs.normalize();
+        let path = preferences_path(device_id);
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        write_atomic(&path, &serde_json::to_vec_pretty(&prefs)?).await?;
+        self.preferences
+            .insert(device_id.to_string(), prefs.clone());
+        Ok(prefs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferences_should_fill_defaults() {
+        let prefs: Preferences = serde_json::from_str(r#"{"voice":"onyx","speed":9}"#).unwrap();
+        let prefs = prefs.normalize();
+        assert_eq!(prefs.voice, Voice::Onyx);
+        assert_eq!(prefs.speed, MAX_SPEED);
+        assert_eq!(prefs.format, AudioFormat::Mp3);
+        assert_eq!(
+            prefs.code_theme(ColorScheme::Dark),
+            CodeTheme::SolarizedDark
+        );
+
+        let prefs: Preferences =
+            serde_json::from_str(r#"{"light_code_theme":"base16_ocean_light"}"#).unwrap();
+        assert_eq!(
+            prefs.code_theme(ColorScheme::Light),
+            CodeTheme::Base16OceanLight
+        );
+    }
+}
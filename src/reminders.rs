@@ -0,0 +1,351 @@
+use crate::{reminders_path, tools::AudioClip, write_atomic, AppState};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{MappedMutexGuard, MutexGuard},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+// further out is most likely a misunderstanding
+const MAX_DELAY_SECONDS: u64 = 365 * 24 * 3600;
+// failed attempts before a reminder is given up, or sent without its audio
+pub(crate) const MAX_FIRE_ATTEMPTS: u32 = 5;
+// wait before the first retry, doubled after each failure
+const RETRY_DELAY_SECONDS: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReminderKind {
+    Reminder,
+    Timer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Reminder {
+    pub(crate) id: String,
+    pub(crate) device_id: String,
+    pub(crate) kind: ReminderKind,
+    /// what to remind of, or the label of the timer
+    pub(crate) message: String,
+    pub(crate) due_at: DateTime<Utc>,
+    pub(crate) created_at: DateTime<Utc>,
+    /// spoken notification, kept until the device is connected to receive it
+    #[serde(default)]
+    pub(crate) audio: Option<AudioClip>,
+    /// failed attempts to fire, only kept in memory
+    #[serde(skip)]
+    pub(crate) attempts: u32,
+    /// when to try again after a failure
+    #[serde(skip)]
+    retry_at: Option<DateTime<Utc>>,
+}
+
+impl Reminder {
+    pub(crate) fn new(
+        device_id: impl Into<String>,
+        kind: ReminderKind,
+        message: impl Into<String>,
+        due_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            device_id: device_id.into(),
+            kind,
+            message: message.into(),
+            due_at,
+            created_at: Utc::now(),
+            audio: None,
+            attempts: 0,
+            retry_at: None,
+        }
+    }
+
+    /// When the reminder is to be fired next, its due time unless it failed.
+    fn next_attempt(&self) -> DateTime<Utc> {
+        self.retry_at.unwrap_or(self.due_at)
+    }
+
+    /// Count a failed attempt and back off, returns false once the attempts are used up.
+    fn retry_later(&mut self, now: DateTime<Utc>) -> bool {
+        self.attempts += 1;
+        let delay = RETRY_DELAY_SECONDS << (self.attempts - 1).min(16);
+        self.retry_at = Some(now + Duration::seconds(delay));
+        self.attempts < MAX_FIRE_ATTEMPTS
+    }
+
+    /// What to say when the reminder fires.
+    pub(crate) fn announcement(&self) -> String {
+        match (self.kind, self.message.is_empty()) {
+            (ReminderKind::Reminder, _) => format!("Reminder: {}", self.message),
+            (ReminderKind::Timer, true) => "Time's up! Your timer is done.".to_string(),
+            (ReminderKind::Timer, false) => {
+                format!("Time's up! Your {} timer is done.", self.message)
+            }
+        }
+    }
+
+    /// Local time the reminder is due, with the date if it isn't today.
+    pub(crate) fn due_time(&self) -> String {
+        let due = self.due_at.with_timezone(&Local);
+        if due.date_naive() == Local::now().date_naive() {
+            due.format("%H:%M").to_string()
+        } else {
+            due.format("%Y-%m-%d %H:%M").to_string()
+        }
+    }
+
+    pub(crate) fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        let message = self.message.to_lowercase();
+        message.contains(&query) || (!message.is_empty() && query.contains(&message))
+    }
+}
+
+impl AppState {
+    pub(crate) async fn add_reminder(&self, reminder: Reminder) -> Result<()> {
+        let mut reminders = self.pending_reminders().await?;
+        reminders.push(reminder);
+        save_reminders(&reminders).await?;
+        self.reminders_changed.notify_one();
+        Ok(())
+    }
+
+    /// Pending reminders and timers of the device, the next due first.
+    pub(crate) async fn reminders(&self, device_id: &str) -> Result<Vec<Reminder>> {
+        let mut reminders: Vec<_> = self
+            .pending_reminders()
+            .await?
+            .iter()
+            .filter(|v| v.device_id == device_id)
+            .cloned()
+            .collect();
+        reminders.sort_by_key(|v| v.due_at);
+        Ok(reminders)
+    }
+
+    /// Reminders of all devices to be fired by now, the failed ones once their retry is due.
+    pub(crate) async fn due_reminders(&self, now: DateTime<Utc>) -> Result<Vec<Reminder>> {
+        let reminders = self.pending_reminders().await?;
+        Ok(reminders
+            .iter()
+            .filter(|v| v.next_attempt() <= now)
+            .cloned()
+            .collect())
+    }
+
+    /// When the next reminder is to be fired after now, none if there is no such reminder.
+    pub(crate) async fn next_reminder_at(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let reminders = self.pending_reminders().await?;
+        Ok(reminders
+            .iter()
+            .map(|v| v.next_attempt())
+            .filter(|v| *v > now)
+            .min())
+    }
+
+    /// Cancel the reminders of the device matching the query, all of them if there is no query.
+    pub(crate) async fn cancel_reminders(
+        &self,
+        device_id: &str,
+        query: Option<&str>,
+    ) -> Result<Vec<Reminder>> {
+        let mut reminders = self.pending_reminders().await?;
+        let matches =
+            |v: &Reminder| v.device_id == device_id && query.map(|q| v.matches(q)).unwrap_or(true);
+        let (cancelled, kept): (Vec<_>, Vec<_>) = reminders.drain(..).partition(matches);
+        *reminders = kept;
+        if !cancelled.is_empty() {
+            save_reminders(&reminders).await?;
+        }
+        Ok(cancelled)
+    }
+
+    /// Save the changes of the reminder, returns false if it was cancelled in the meantime.
+    pub(crate) async fn update_reminder(&self, reminder: &Reminder) -> Result<bool> {
+        let mut reminders = self.pending_reminders().await?;
+        match reminders.iter_mut().find(|v| v.id == reminder.id) {
+            Some(v) => *v = reminder.clone(),
+            None => return Ok(false),
+        }
+        save_reminders(&reminders).await?;
+        Ok(true)
+    }
+
+    /// Deliver the reminder with `send` and remove it once delivered. It's checked to be still
+    /// pending under the same lock, so a reminder cancelled in the meantime is never delivered.
+    /// Returns false if it was cancelled or not delivered.
+    pub(crate) async fn send_reminder(
+        &self,
+        id: &str,
+        send: impl FnOnce() -> bool,
+    ) -> Result<bool> {
+        let mut reminders = self.pending_reminders().await?;
+        if !reminders.iter().any(|v| v.id == id) || !send() {
+            return Ok(false);
+        }
+        reminders.retain(|v| v.id != id);
+        save_reminders(&reminders).await?;
+        Ok(true)
+    }
+
+    /// Back off the reminder that failed to fire, it's dropped once it failed too many times.
+    /// Returns false if it was dropped.
+    pub(crate) async fn retry_reminder(&self, id: &str, now: DateTime<Utc>) -> Result<bool> {
+        let mut reminders = self.pending_reminders().await?;
+        let Some(i) = reminders.iter().position(|v| v.id == id) else {
+            return Ok(true);
+        };
+        if reminders[i].retry_later(now) {
+            return Ok(true);
+        }
+        reminders.remove(i);
+        save_reminders(&reminders).await?;
+        Ok(false)
+    }
+
+    /// The reminders of all devices, loaded from the file on first use and kept in memory since.
+    /// Holding the guard serializes the updates.
+    async fn pending_reminders(&self) -> Result<MappedMutexGuard<'_, Vec<Reminder>>> {
+        let mut reminders = self.reminders.lock().await;
+        if reminders.is_none() {
+            *reminders = Some(load_reminders().await?);
+        }
+        Ok(MutexGuard::map(reminders, |v| {
+            v.get_or_insert_with(Vec::new)
+        }))
+    }
+}
+
+async fn load_reminders() -> Result<Vec<Reminder>> {
+    let data = match fs::read(reminders_path()).await {
+        Ok(data) => data,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn!("invalid reminders: {}", e);
+        vec![]
+    }))
+}
+
+async fn save_reminders(reminders: &[Reminder]) -> Result<()> {
+    let path = reminders_path();
+    if let Some(parent) = path.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent).await?;
+        }
+    }
+    write_atomic(&path, &serde_json::to_vec_pretty(reminders)?).await
+}
+
+/// When a reminder is due, either after the delay or at the local time given as "HH:MM" or
+/// "YYYY-MM-DD HH:MM". A time of day already passed means tomorrow.
+pub(crate) fn due_at(
+    now: DateTime<Local>,
+    delay_seconds: Option<u64>,
+    at: Option<&str>,
+) -> Result<DateTime<Utc>> {
+    if let Some(secs) = delay_seconds {
+        return after_delay(now.with_timezone(&Utc), secs);
+    }
+    let Some(at) = at.map(str::trim) else {
+        bail!("no time given for the reminder");
+    };
+    let datetime = if let Ok(v) = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M") {
+        v
+    } else if let Ok(time) = NaiveTime::parse_from_str(at, "%H:%M") {
+        let today = now.date_naive().and_time(time);
+        if today > now.naive_local() {
+            today
+        } else {
+            today + Duration::days(1)
+        }
+    } else {
+        bail!("can't understand the reminder time {}", at);
+    };
+    match Local.from_local_datetime(&datetime).earliest() {
+        Some(v) if v > now => Ok(v.with_timezone(&Utc)),
+        Some(_) => bail!("the reminder time {} has already passed", at),
+        None => bail!("the reminder time {} doesn't exist", at),
+    }
+}
+
+/// When a reminder or timer set for the delay is due, up to a year from now.
+pub(crate) fn after_delay(now: DateTime<Utc>, secs: u64) -> Result<DateTime<Utc>> {
+    if secs > MAX_DELAY_SECONDS {
+        bail!("I can only set reminders and timers up to a year ahead");
+    }
+    now.checked_add_signed(Duration::seconds(secs as i64))
+        .ok_or_else(|| anyhow!("{} seconds from now is out of range", secs))
+}
+
+/// Spoken form of a duration, e.g. "1 hour and 20 minutes".
+pub(crate) fn format_duration(secs: u64) -> String {
+    let units = [
+        (secs / 3600, "hour"),
+        ((secs % 3600) / 60, "minute"),
+        (secs % 60, "second"),
+    ];
+    let parts: Vec<_> = units
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, unit)| format!("{} {}{}", n, unit, if *n > 1 { "s" } else { "" }))
+        .collect();
+    match parts.as_slice() {
+        [] => "0 seconds".to_string(),
+        [rest @ .., last] if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        _ => parts.join(""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due_at_should_handle_delay_and_time_of_day() {
+        let now = Local.with_ymd_and_hms(2024, 3, 1, 9, 30, 0).unwrap();
+        let due = due_at(now, Some(20 * 60), None).unwrap();
+        assert_eq!(due, (now + Duration::minutes(20)).with_timezone(&Utc));
+        assert!(due_at(now, Some(u64::MAX), None).is_err());
+        assert!(due_at(now, Some(MAX_DELAY_SECONDS + 1), None).is_err());
+
+        let due = due_at(now, None, Some("17:00"))
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(due.format("%Y-%m-%d %H:%M").to_string(), "2024-03-01 17:00");
+        let due = due_at(now, None, Some("08:00"))
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(due.format("%Y-%m-%d %H:%M").to_string(), "2024-03-02 08:00");
+
+        assert!(due_at(now, None, Some("2024-02-01 10:00")).is_err());
+        assert!(due_at(now, None, Some("tomorrow")).is_err());
+        assert!(due_at(now, None, None).is_err());
+
+        assert_eq!(format_duration(20 * 60), "20 minutes");
+        assert_eq!(
+            format_duration(3600 + 60 + 5),
+            "1 hour, 1 minute and 5 seconds"
+        );
+        assert_eq!(format_duration(90), "1 minute and 30 seconds");
+    }
+
+    #[test]
+    fn test_retry_later_should_back_off_until_given_up() {
+        let now = Utc::now();
+        let mut reminder = Reminder::new("dev", ReminderKind::Timer, "", now);
+        assert_eq!(reminder.next_attempt(), now);
+        let mut delays = vec![];
+        while reminder.retry_later(now) {
+            delays.push((reminder.next_attempt() - now).num_seconds());
+        }
+        assert_eq!(delays, [10, 20, 40, 80]);
+        assert_eq!(reminder.attempts, MAX_FIRE_ATTEMPTS);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    sandbox_path,
+    tools::{CodeLanguage, ExecutionOutput},
+};
+use anyhow::{anyhow, Result};
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::unix::{ffi::OsStrExt, fs::chown, process::ExitStatusExt},
+    path::Path,
+    process::Stdio,
+    ptr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    task::JoinHandle,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+// the search path inside the sandbox, whose root only has the code's dir, a tmp dir, a few
+// devices and the system dirs below mounted read-only
+const SANDBOX_PATH_ENV: &str = "/usr/local/bin:/usr/bin:/bin";
+// where the interpreters and their libraries are, symlinks (e.g. /bin -> usr/bin) are kept as such
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+// the code's user inside its user namespace, not root so that exec drops all the capabilities
+const SANDBOX_ID: u32 = 1000;
+// the host user the sandbox runs as when the server is root, RLIMIT_NPROC doesn't apply to root
+const NOBODY: u32 = 65534;
+
+#[derive(Debug, Clone)]
+pub(crate) struct SandboxLimits {
+    /// cpu time of the process
+    pub(crate) cpu_seconds: u64,
+    /// heap size of the process
+    pub(crate) memory_bytes: u64,
+    /// max size of any file the process writes
+    pub(crate) file_size_bytes: u64,
+    /// max number of open file descriptors
+    pub(crate) open_files: u64,
+    /// max number of processes and threads, all of the sandbox together
+    pub(crate) processes: u64,
+    /// size of the in-memory root, which holds /tmp
+    pub(crate) tmp_bytes: u64,
+    /// wall time before the whole sandbox is killed
+    pub(crate) timeout: Duration,
+    /// max bytes kept from stdout and stderr each
+    pub(crate) output_bytes: usize,
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        Self {
+            cpu_seconds: 5,
+            memory_bytes: 256 * 1024 * 1024,
+            file_size_bytes: 1024 * 1024,
+            open_files: 64,
+            processes: 32,
+            tmp_bytes: 16 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+            output_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// The root of the sandbox, prepared before the fork since nothing can be allocated after it.
+struct Jail {
+    /// host dir the tmpfs root is mounted on
+    root: CString,
+    tmpfs_options: CString,
+    /// dirs to create in the root, with their mode
+    dirs: Vec<(CString, libc::mode_t)>,
+    /// (target, link) symlinks to create in the root
+    links: Vec<(CString, CString)>,
+    /// empty files to create in the root, to mount the devices on
+    files: Vec<CString>,
+    binds: Vec<Bind>,
+    uid_map: CString,
+    gid_map: CString,
+}
+
+struct Bind {
+    source: CString,
+    target: CString,
+    /// flags to remount the bind with, for the read-only ones
+    remount: Option<libc::c_ulong>,
+}
+
+impl CodeLanguage {
+    fn command(&self) -> (&'static str, &'static str) {
+        match self {
+            CodeLanguage::Python => ("python3", "main.py"),
+            CodeLanguage::Javascript => ("node", "main.js"),
+            CodeLanguage::Shell => ("sh", "main.sh"),
+        }
+    }
+}
+
+/// Run the code in a throwaway directory, with the resource limits applied. The process is
+/// jailed in its own user, mount, pid and network namespaces: its root is an in-memory dir
+/// holding only its own dir and the system dirs read-only, it sees no other process and has
+/// no network.
+pub(crate) async fn run_code(
+    language: CodeLanguage,
+    code: &str,
+    limits: &SandboxLimits,
+) -> Result<ExecutionOutput> {
+    let dir = sandbox_path(&Uuid::new_v4().to_string());
+    fs::create_dir_all(&dir).await?;
+    let ret = execute(language, code, limits, &dir).await;
+    if let Err(e) = fs::remove_dir_all(&dir).await {
+        warn!("failed to remove sandbox {}: {}", dir.display(), e);
+    }
+    ret
+}
+
+async fn execute(
+    language: CodeLanguage,
+    code: &str,
+    limits: &SandboxLimits,
+    dir: &Path,
+) -> Result<ExecutionOutput> {
+    let (program, file) = language.command();
+    let work = dir.join("work");
+    fs::create_dir(&work).await?;
+    fs::create_dir(dir.join("root")).await?;
+    fs::write(work.join(file), code).await?;
+
+    // SAFETY: getuid and getgid never fail
+    let (uid, gid) = match unsafe { (libc::getuid(), libc::getgid()) } {
+        (0, _) => (NOBODY, NOBODY),
+        ids => ids,
+    };
+    let mut cmd = Command::new(program);
+    if uid == NOBODY {
+        chown(&work, Some(uid), Some(gid))?;
+        chown(work.join(file), Some(uid), Some(gid))?;
+        cmd.uid(uid).gid(gid);
+    }
+    cmd.arg(file)
+        .env_clear()
+        .env("PATH", SANDBOX_PATH_ENV)
+        .env("HOME", "/work")
+        .env("TMPDIR", "/tmp")
+        .env("LANG", "C.UTF-8")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let jail = Jail::new(dir, limits, uid, gid)?;
+    // (resource, soft, hard), SIGXCPU is sent at the soft cpu limit, SIGKILL at the hard one
+    let rlimits = [
+        (libc::RLIMIT_CPU, limits.cpu_seconds, limits.cpu_seconds + 1),
+        (libc::RLIMIT_DATA, limits.memory_bytes, limits.memory_bytes),
+        (
+            libc::RLIMIT_FSIZE,
+            limits.file_size_bytes,
+            limits.file_size_bytes,
+        ),
+        (libc::RLIMIT_NOFILE, limits.open_files, limits.open_files),
+        (libc::RLIMIT_CORE, 0, 0),
+    ];
+    let processes = limits.processes;
+    // SAFETY: only async-signal-safe libc calls are made between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            check(libc::setsid())?;
+            for (resource, soft, hard) in rlimits {
+                set_rlimit(resource, soft, hard)?;
+            }
+            // a fresh network namespace only has a loopback device which is down
+            check(libc::unshare(
+                libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET,
+            ))?;
+            // dropping root made /proc/self root's, the maps can't be written otherwise
+            check(libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0))?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", jail.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", jail.gid_map.as_bytes())?;
+            // counted in the new user namespace, so only the sandbox's processes are
+            set_rlimit(libc::RLIMIT_NPROC, processes, processes)?;
+
+            // the child is pid 1 of the new pid namespace, the kernel kills all of the
+            // namespace when it dies, even the processes that left the process group. It
+            // sends back the wait status of the code through the pipe.
+            let mut status_pipe = [0; 2];
+            check(libc::pipe2(status_pipe.as_mut_ptr(), libc::O_CLOEXEC))?;
+            let child = libc::fork();
+            if child < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if child == 0 {
+                libc::close(status_pipe[0]);
+                jail.enter()?;
+                return run_init(status_pipe[1]);
+            }
+            libc::close(status_pipe[1]);
+            wait_and_exit(child, status_pipe[0])
+        });
+    }
+
+    let start = Instant::now();
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| anyhow!("failed to start the sandbox for {}: {}", language, e))?;
+    let pid = child.id();
+    let stdout = capture(child.stdout.take(), limits.output_bytes);
+    let stderr = capture(child.stderr.take(), limits.output_bytes);
+
+    let (status, timed_out) = match tokio::time::timeout(limits.timeout, child.wait()).await {
+        Ok(status) => (status?, false),
+        // not reaped yet, so the process group can't have been reused: killing the sandbox's
+        // pid 1 along with the process waiting for it takes down the rest of the pid namespace
+        Err(_) => match child.try_wait()? {
+            Some(status) => (status, false),
+            None => {
+                if let Some(pid) = pid {
+                    unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+                }
+                (child.wait().await?, true)
+            }
+        },
+    };
+
+    let (stdout, stdout_truncated) = stdout.await??;
+    let (stderr, stderr_truncated) = stderr.await??;
+    Ok(ExecutionOutput {
+        stdout,
+        stderr,
+        exit_code: status.code(),
+        signal: status.signal().filter(|_| !timed_out),
+        timed_out: timed_out || status.signal() == Some(libc::SIGXCPU),
+        truncated: stdout_truncated || stderr_truncated,
+        duration_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+impl Jail {
+    /// The root mounted on dir/root, with dir/work as /work.
+    fn new(dir: &Path, limits: &SandboxLimits, uid: u32, gid: u32) -> Result<Self> {
+        let root = dir.join("root");
+        let in_root = |path: &str| cstring(&root.join(path.trim_start_matches('/')));
+        let mut jail = Self {
+            root: cstring(&root)?,
+            tmpfs_options: CString::new(format!("size={},mode=755", limits.tmp_bytes))?,
+            dirs: vec![
+                (in_root("work")?, 0o755),
+                (in_root("tmp")?, 0o1777),
+                (in_root("dev")?, 0o755),
+                (in_root("proc")?, 0o555),
+                (in_root(".old")?, 0o700),
+            ],
+            links: vec![],
+            files: vec![],
+            binds: vec![],
+            uid_map: CString::new(format!("{} {} 1", SANDBOX_ID, uid))?,
+            gid_map: CString::new(format!("{} {} 1", SANDBOX_ID, gid))?,
+        };
+        for path in SYSTEM_DIRS {
+            let Ok(meta) = std::fs::symlink_metadata(path) else {
+                continue;
+            };
+            if meta.is_symlink() {
+                let target = std::fs::read_link(path)?;
+                jail.links.push((cstring(&target)?, in_root(path)?));
+            } else if meta.is_dir() {
+                let source = cstring(Path::new(path))?;
+                jail.dirs.push((in_root(path)?, 0o755));
+                jail.binds.push(Bind {
+                    remount: Some(read_only_flags(&source)?),
+                    source,
+                    target: in_root(path)?,
+                });
+            }
+        }
+        for path in DEVICES {
+            if Path::new(path).exists() {
+                jail.files.push(in_root(path)?);
+                jail.binds.push(Bind {
+                    source: cstring(Path::new(path))?,
+                    target: in_root(path)?,
+                    remount: None,
+                });
+            }
+        }
+        jail.binds.push(Bind {
+            source: cstring(&dir.join("work"))?,
+            target: in_root("work")?,
+            remount: None,
+        });
+        Ok(jail)
+    }
+
+    /// Switch to the root, called by pid 1 of the sandbox before exec.
+    unsafe fn enter(&self) -> io::Result<()> {
+        check(libc::prctl(
+            libc::PR_SET_PDEATHSIG,
+            libc::SIGKILL as libc::c_ulong,
+            0,
+            0,
+            0,
+        ))?;
+        let null = ptr::null();
+        check(libc::mount(
+            null,
+            c"/".as_ptr(),
+            null,
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        ))?;
+        check(libc::mount(
+            c"tmpfs".as_ptr(),
+            self.root.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            self.tmpfs_options.as_ptr().cast(),
+        ))?;
+        for (dir, mode) in &self.dirs {
+            check(libc::mkdir(dir.as_ptr(), *mode))?;
+            check(libc::chmod(dir.as_ptr(), *mode))?;
+        }
+        for (target, link) in &self.links {
+            check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+        }
+        for file in &self.files {
+            let fd = libc::open(
+                file.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                0o644,
+            );
+            check(fd)?;
+            libc::close(fd);
+        }
+        for bind in &self.binds {
+            check(libc::mount(
+                bind.source.as_ptr(),
+                bind.target.as_ptr(),
+                null,
+                libc::MS_BIND | libc::MS_REC,
+                ptr::null(),
+            ))?;
+            if let Some(flags) = bind.remount {
+                check(libc::mount(
+                    null,
+                    bind.target.as_ptr(),
+                    null,
+                    flags,
+                    ptr::null(),
+                ))?;
+            }
+        }
+
+        check(libc::chdir(self.root.as_ptr()))?;
+        check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".old".as_ptr()) as libc::c_int)?;
+        check(libc::umount2(c"/.old".as_ptr(), libc::MNT_DETACH))?;
+        check(libc::rmdir(c"/.old".as_ptr()))?;
+        // best effort, e.g. node reads it, it can't be mounted in some containers
+        libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            ptr::null(),
+        );
+        check(libc::chdir(c"/work".as_ptr()))?;
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        Ok(())
+    }
+}
+
+/// Fork the code as pid 2, pid 1 ignores the signals it has no handler for, e.g. SIGXCPU.
+/// Pid 1 reaps the orphans until the code exits, then sends its wait status.
+unsafe fn run_init(status_fd: libc::c_int) -> io::Result<()> {
+    let child = libc::fork();
+    if child < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if child == 0 {
+        libc::close(status_fd);
+        return Ok(());
+    }
+    close_fds(status_fd);
+    let mut status = 0;
+    loop {
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == child {
+            libc::write(status_fd, (&status as *const libc::c_int).cast(), 4);
+            libc::_exit(0);
+        }
+        if pid < 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(127);
+        }
+    }
+}
+
+/// Wait for the sandbox's pid 1 and exit the same way as the code, so that the server sees
+/// its status.
+unsafe fn wait_and_exit(child: libc::pid_t, status_fd: libc::c_int) -> ! {
+    close_fds(status_fd);
+    let mut status = 0;
+    while libc::waitpid(child, &mut status, 0) < 0 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(127);
+        }
+    }
+    // pid 1 failed before running the code or was killed otherwise
+    let mut code_status = 0;
+    if libc::read(status_fd, (&mut code_status as *mut libc::c_int).cast(), 4) == 4 {
+        status = code_status;
+    }
+    if libc::WIFEXITED(status) {
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+    let signal = libc::WTERMSIG(status);
+    libc::signal(signal, libc::SIG_DFL);
+    libc::kill(libc::getpid(), signal);
+    libc::_exit(128 + signal)
+}
+
+/// Close the inherited fds but stdio and the one kept, among them the pipe the server waits
+/// on to know that exec succeeded.
+unsafe fn close_fds(keep: libc::c_int) {
+    for (first, last) in [(3, keep - 1), (keep + 1, libc::c_int::MAX)] {
+        if first > last {
+            continue;
+        }
+        if libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            last as libc::c_uint,
+            0,
+        ) < 0
+        {
+            for fd in first..last.min(1024) {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Flags to remount a bind of the path read-only with, the ones of its mount are kept since
+/// they can't be cleared in a user namespace.
+fn read_only_flags(path: &CStr) -> io::Result<libc::c_ulong> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+    let mut flags =
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+    for (st, ms) in [
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    Ok(flags)
+}
+
+unsafe fn set_rlimit(resource: libc::__rlimit_resource_t, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    check(libc::setrlimit(resource, &limit))
+}
+
+unsafe fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, data.as_ptr().cast(), data.len());
+    libc::close(fd);
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::from)
+}
+
+/// Read the stream up to the limit and drain the rest, so that the process never blocks on a
+/// full pipe.
+fn capture<R>(reader: Option<R>, limit: usize) -> JoinHandle<io::Result<(String, bool)>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let Some(reader) = reader else {
+            return Ok((String::new(), false));
+        };
+        let mut buf = Vec::new();
+        let mut reader = reader.take(limit as u64);
+        reader.read_to_end(&mut buf).await?;
+        let rest = tokio::io::copy(&mut reader.into_inner(), &mut tokio::io::sink()).await?;
+        Ok((String::from_utf8_lossy(&buf).into_owned(), rest > 0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_code_should_capture_output_and_exit_code() {
+        let code = "echo hello\necho oops >&2\nexit 3\n";
+        let ret = run_code(CodeLanguage::Shell, code, &SandboxLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(ret.stdout, "hello\n");
+        assert_eq!(ret.stderr, "oops\n");
+        assert_eq!(ret.exit_code, Some(3));
+        assert!(!ret.timed_out);
+    }
+
+    #[tokio::test]
+    async fn test_run_code_should_be_limited() {
+        let limits = SandboxLimits {
+            timeout: Duration::from_millis(500),
+            output_bytes: 16,
+            ..Default::default()
+        };
+        let ret = run_code(CodeLanguage::Shell, "yes\n", &limits)
+            .await
+            .unwrap();
+        assert!(ret.timed_out);
+        assert!(ret.truncated);
+        assert_eq!(ret.stdout.len(), 16);
+
+        let limits = SandboxLimits {
+            cpu_seconds: 1,
+            ..Default::default()
+        };
+        let ret = run_code(CodeLanguage::Shell, "while :; do :; done\n", &limits)
+            .await
+            .unwrap();
+        assert!(ret.timed_out);
+        assert!(ret.duration_ms < 5000);
+
+        // killed otherwise, not out of time
+        let ret = run_code(CodeLanguage::Shell, "kill -9 $$\n", &limits)
+            .await
+            .unwrap();
+        assert!(!ret.timed_out);
+        assert_eq!(ret.signal, Some(libc::SIGKILL));
+        assert_eq!(ret.status(), "killed by SIGKILL");
+
+        let code = "import socket\nsocket.create_connection(('1.1.1.1', 53), timeout=2)\n";
+        let ret = run_code(CodeLanguage::Python, code, &SandboxLimits::default())
+            .await
+            .unwrap();
+        assert_ne!(ret.exit_code, Some(0));
+        assert!(ret.stderr.contains("unreachable"), "{}", ret.stderr);
+    }
+
+    #[tokio::test]
+    async fn test_run_code_should_not_reach_outside_its_dir() {
+        // exists on the host, not in the sandbox
+        let outside = sandbox_path(&format!("escape-{}", Uuid::new_v4()));
+        let code = format!(
+            "echo kept > out.txt && cat out.txt\n\
+             echo escaped > {} 2>/dev/null || echo no-write\n\
+             touch /usr/escaped 2>/dev/null || echo read-only\n\
+             kill -9 {} 2>/dev/null || echo no-kill\n\
+             setsid sleep 30 &\n\
+             echo $$\n",
+            outside.display(),
+            std::process::id()
+        );
+        let ret = run_code(CodeLanguage::Shell, &code, &SandboxLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(ret.stdout, "kept\nno-write\nread-only\nno-kill\n2\n");
+        assert!(!outside.exists());
+        // the detached sleep was killed along with the sandbox
+        assert!(!ret.timed_out);
+        assert!(ret.duration_ms < 5000);
+
+        let code = "import os\nfor _ in range(64):\n    os.fork() or os._exit(0)\n";
+        let ret = run_code(CodeLanguage::Python, code, &SandboxLimits::default())
+            .await
+            .unwrap();
+        assert!(ret.stderr.contains("BlockingIOError"), "{}", ret.stderr);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::{images::Thumbnail, preferences::AudioFormat};
 use askama::Template;
-use llm_sdk::{ChatCompletionMessage, ChatCompletionRequest, Tool};
+use chrono::Local;
+use llm_sdk::{
+    ChatCompletionMessage, ChatCompletionRequest, ImageQuality, ImageSize, ImageStyle, Tool,
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString};
@@ -14,21 +18,163 @@ pub(crate) enum AssistantTool {
     WriteCode,
     /// Just reply based on user's input
     Answer,
+    /// Turn voice translation mode on or off
+    TranslationMode,
+    /// Explain a topic with an illustration and code examples
+    Explain,
+    /// Edit the image drawn previously
+    EditImage,
+    /// Write code and run it in the sandbox
+    RunCode,
+    /// Plot the data as a chart
+    DrawChart,
+    /// Add a to-do to the task list
+    AddTask,
+    /// Read out the open to-dos
+    ListTasks,
+    /// Mark a to-do as done
+    FinishTask,
+    /// Remind the user of something later
+    SetReminder,
+    /// Start a countdown timer
+    SetTimer,
+    /// Read out the pending reminders and timers
+    ListReminders,
+    /// Cancel pending reminders or timers
+    CancelReminder,
+    /// Calculate math, unit conversions and dates exactly
+    Calculate,
+    /// Read a web page and answer about it
+    FetchUrl,
+    /// Answer from the documents uploaded by the user
+    SearchDocuments,
+    /// Keep a fact about the user for later conversations
+    Remember,
+    /// Drop facts remembered before
+    Forget,
+    /// Read out the remembered facts
+    Recall,
 }
 
+const MAX_IMAGE_COUNT: usize = 4;
+
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub(crate) struct DrawImageArgs {
     /// The revised prompt for creating the image
     pub(crate) prompt: String,
+    /// The aspect ratio of the image, e.g. "wide" for a banner or "tall" for a poster
+    #[serde(default)]
+    pub(crate) size: ImageAspect,
+    /// The quality of the image, "hd" if the user asks for high quality or details
+    #[serde(default)]
+    pub(crate) quality: DrawImageQuality,
+    /// The style of the image, "natural" for realistic photos, "vivid" for dramatic images
+    #[serde(default)]
+    pub(crate) style: DrawImageStyle,
+    /// How many images to draw, from 1 to 4, e.g. 3 if the user asks for three options
+    #[serde(default = "default_image_count")]
+    pub(crate) count: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImageAspect {
+    /// 1024x1024
+    #[default]
+    Square,
+    /// 1792x1024
+    Wide,
+    /// 1024x1792
+    Tall,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DrawImageQuality {
+    #[default]
+    Standard,
+    Hd,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DrawImageStyle {
+    #[default]
+    Vivid,
+    Natural,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "blocks/image.html.j2")]
 pub(crate) struct DrawImageResult {
+    /// generated images, empty while drawing
+    pub(crate) images: Vec<GeneratedImage>,
+    /// revised prompt
+    pub(crate) prompt: String,
+    /// number of images requested
+    pub(crate) count: usize,
+    /// spoken summary of the image
+    pub(crate) audio: Option<AudioClip>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GeneratedImage {
+    /// image id, decides the image path
+    pub(crate) id: String,
     /// image url
     pub(crate) url: String,
-    /// revised prompt
+    /// downscaled preview of the image
+    pub(crate) preview: Option<ImagePreview>,
+    /// revised prompt of this image
     pub(crate) prompt: String,
+    /// the instruction used to edit the previous image into this one
+    pub(crate) edit: Option<String>,
+    /// the images this one was edited from, oldest first
+    pub(crate) lineage: Vec<ImageLineageStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ImagePreview {
+    /// thumbnail url
+    pub(crate) url: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// blurhash shown while the thumbnail is loading
+    pub(crate) blurhash: String,
+    /// data url of the blurhash, decoded when the thumbnail was saved
+    #[serde(default)]
+    pub(crate) placeholder: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ImageLineageStep {
+    /// image url
+    pub(crate) url: String,
+    /// how the image was created, "original" or the edit instruction
+    pub(crate) label: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct EditImageArgs {
+    /// The instruction for editing the image, e.g. "make it darker"
+    pub(crate) instruction: String,
+    /// "edit" to change the image following the instruction, "variation" for similar images
+    #[serde(default)]
+    pub(crate) mode: EditImageMode,
+    /// Which of the previous images to edit, starting from 1, e.g. 3 for "the third one"
+    #[serde(default)]
+    pub(crate) index: Option<usize>,
+    /// How many images to create, from 1 to 4
+    #[serde(default = "default_image_count")]
+    pub(crate) count: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EditImageMode {
+    #[default]
+    Edit,
+    Variation,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
@@ -36,6 +182,37 @@ pub(crate) struct DrawImageResult {
 pub(crate) struct WriteCodeResult {
     /// revised prompt
     pub(crate) content: String,
+    /// the code blocks in the content, stored as files
+    pub(crate) files: Vec<CodeFile>,
+    /// url of the zip archive of all the files, only for multiple files
+    pub(crate) archive: Option<String>,
+    /// spoken summary of the code
+    pub(crate) audio: Option<AudioClip>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CodeFile {
+    /// relative path of the file, e.g. "src/main.rs"
+    pub(crate) name: String,
+    /// language of the code, e.g. "rust"
+    pub(crate) language: String,
+    /// download url
+    pub(crate) url: String,
+}
+
+/// A piece of the rendered content, starting with the code block of the file if any.
+#[derive(Debug, Clone)]
+pub(crate) struct CodeSegment<'a> {
+    pub(crate) file: Option<&'a CodeFile>,
+    pub(crate) html: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AudioClip {
+    /// audio url
+    pub(crate) url: String,
+    /// audio format, decides the mime type
+    pub(crate) format: AudioFormat,
 }
 
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
@@ -44,20 +221,259 @@ pub(crate) struct WriteCodeArgs {
     pub(crate) prompt: String,
 }
 
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct RunCodeArgs {
+    /// The revised prompt for the code to write and run, e.g. "print the first 10 primes"
+    pub(crate) prompt: String,
+    /// The language of the code, python unless the user asks for another one
+    #[serde(default)]
+    pub(crate) language: CodeLanguage,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum CodeLanguage {
+    #[default]
+    Python,
+    Javascript,
+    Shell,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "blocks/code_output.html.j2")]
+pub(crate) struct RunCodeResult {
+    /// language of the code
+    pub(crate) language: CodeLanguage,
+    /// the output of the execution, none while running
+    pub(crate) output: Option<ExecutionOutput>,
+    /// spoken summary of the code and its output
+    pub(crate) audio: Option<AudioClip>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExecutionOutput {
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    /// exit code, none if the process was killed by a signal
+    pub(crate) exit_code: Option<i32>,
+    /// signal that killed the process, e.g. SIGKILL when out of memory
+    #[serde(default)]
+    pub(crate) signal: Option<i32>,
+    /// whether the process was killed for running out of time
+    pub(crate) timed_out: bool,
+    /// whether stdout or stderr was cut at the output limit
+    pub(crate) truncated: bool,
+    /// wall time in milliseconds
+    pub(crate) duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct DrawChartArgs {
+    /// The title of the chart
+    pub(crate) title: String,
+    /// The type of the chart, "line" for trends, "bar" for comparisons, "pie" for parts of a whole
+    #[serde(default)]
+    pub(crate) kind: ChartKind,
+    /// The labels of the data points on the x axis, or of the slices of a pie chart
+    #[serde(default)]
+    pub(crate) labels: Vec<String>,
+    /// The data series, one value per label. A pie chart only uses the first one
+    pub(crate) series: Vec<ChartSeries>,
+    /// The description of the x axis, e.g. "Month"
+    #[serde(default)]
+    pub(crate) x_label: Option<String>,
+    /// The description of the y axis, e.g. "Revenue ($)"
+    #[serde(default)]
+    pub(crate) y_label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct ChartSeries {
+    /// The name of the series shown in the legend, e.g. "2023"
+    #[serde(default)]
+    pub(crate) name: String,
+    /// The values of the series
+    pub(crate) values: Vec<f64>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum ChartKind {
+    #[default]
+    Line,
+    Bar,
+    Pie,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Template)]
+#[template(path = "blocks/chart.html.j2")]
+pub(crate) struct DrawChartResult {
+    pub(crate) title: String,
+    pub(crate) kind: ChartKind,
+    /// url of the rendered svg, none while drawing
+    pub(crate) url: Option<String>,
+    /// spoken summary of the chart
+    pub(crate) audio: Option<AudioClip>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct AddTaskArgs {
+    /// The short title of the to-do, e.g. "buy milk"
+    pub(crate) title: String,
+    /// The priority of the to-do, medium unless the user says otherwise
+    #[serde(default)]
+    pub(crate) priority: TaskPriority,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct ListTasksArgs {}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct FinishTaskArgs {
+    /// The title of the to-do that is done, as the user said it
+    pub(crate) title: String,
+}
+
+/// Priority of a to-do, displayed as the option name of the Notion select property.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Display,
+    EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(ascii_case_insensitive)]
+pub(crate) enum TaskPriority {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct SetReminderArgs {
+    /// What to remind the user of, e.g. "stand up"
+    pub(crate) message: String,
+    /// Seconds from now if the user gives a relative time, e.g. 1200 for "in 20 minutes"
+    #[serde(default)]
+    pub(crate) delay_seconds: Option<u64>,
+    /// Local time if the user gives a time of day, "HH:MM" or "YYYY-MM-DD HH:MM"
+    #[serde(default)]
+    pub(crate) at: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct SetTimerArgs {
+    /// The length of the timer in seconds, e.g. 300 for five minutes
+    pub(crate) duration_seconds: u64,
+    /// What the timer is for, e.g. "pasta"
+    #[serde(default)]
+    pub(crate) label: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct ListRemindersArgs {}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct CancelReminderArgs {
+    /// The reminder or timer to cancel as the user said it, e.g. "stand up". Omit it to cancel all
+    #[serde(default)]
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct CalculateArgs {
+    /// The expression in fend calculator syntax, e.g. "(3 + 4) * 2", "15% of 80", "5 miles to km", "100 fahrenheit to celsius", "3 hours + 20 min to min", "@2024-03-01 + 30 days", "@2024-12-25 - today"
+    pub(crate) expression: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct FetchUrlArgs {
+    /// The full url of the page, e.g. "https://blog.rust-lang.org/"
+    pub(crate) url: String,
+    /// What the user wants from the page, e.g. "summarize the article"
+    #[serde(default = "default_fetch_prompt")]
+    pub(crate) prompt: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct SearchDocumentsArgs {
+    /// The question to look up in the documents, e.g. "what is the notice period in my lease"
+    pub(crate) query: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct RememberArgs {
+    /// The fact or preference in the user's words, e.g. "my daughter is allergic to peanuts"
+    pub(crate) content: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct ForgetArgs {
+    /// What to forget as the user said it, e.g. "peanuts"
+    pub(crate) content: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct RecallArgs {
+    /// What the user asks about, e.g. "my daughter". Omit it to recall everything
+    #[serde(default)]
+    pub(crate) query: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub(crate) struct AnswerArgs {
     /// question or prompt from user
     pub(crate) prompt: String,
 }
 
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct ExplainArgs {
+    /// The topic or question to explain
+    pub(crate) prompt: String,
+    /// The prompt for drawing an illustration, only if a picture helps the explanation
+    #[serde(default)]
+    pub(crate) illustration: Option<String>,
+    /// The prompt for writing example code, only if code helps the explanation
+    #[serde(default)]
+    pub(crate) code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TranslationModeArgs {
+    /// Whether translation mode should be enabled or disabled
+    pub(crate) enabled: bool,
+    /// The language to translate into, e.g. "English" or "French". Defaults to English
+    #[serde(default)]
+    pub(crate) target_language: Option<String>,
+}
+
+/// The request to pick a tool for the input following the history, with the memories relevant
+/// to it appended to the system prompt.
 pub(crate) fn tool_completion_request(
     input: impl Into<String>,
     name: &str,
+    memories: &str,
+    history: &[ChatCompletionMessage],
 ) -> ChatCompletionRequest {
-    let messages = vec![
-      ChatCompletionMessage::new_system("I can help to identify which tool to use, if no proper tool could be used, I'll directly reply the message with pure text", "Ava"),
-      ChatCompletionMessage::new_user(input.into(), name)
+    let mut messages = vec![
+      ChatCompletionMessage::new_system(format!("I can help to identify which tool to use, if no proper tool could be used, I'll directly reply the message with pure text. Any arithmetic, unit conversion or date calculation always goes to the calculate tool, never a direct answer. The current local time is {}{}", Local::now().format("%Y-%m-%d %H:%M, %A"), memories), "Ava"),
       ];
+    messages.extend_from_slice(history);
+    messages.push(ChatCompletionMessage::new_user(input.into(), name));
     ChatCompletionRequest::new_with_tools(messages, all_tools())
 }
 
@@ -67,22 +483,456 @@ fn all_tools() -> Vec<Tool> {
         Tool::new_function::<DrawImageArgs>("draw_image", "Draw an image based on the prompt."),
         Tool::new_function::<WriteCodeArgs>("write_code", "Write code based on the prompt."),
         Tool::new_function::<AnswerArgs>("answer", "Just reply based on the prompt."),
+        Tool::new_function::<CalculateArgs>(
+            "calculate",
+            "Calculate exactly instead of answering: arithmetic, percentages, unit conversions (not currencies), dates and durations, e.g. \"what's 5 miles in km\".",
+        ),
+        Tool::new_function::<RunCodeArgs>(
+            "run_code",
+            "Write a small program and run it to compute or show the result, e.g. \"run a script that prints the first 10 primes\".",
+        ),
+        Tool::new_function::<DrawChartArgs>(
+            "draw_chart",
+            "Plot the data given by the user as a line, bar or pie chart, e.g. \"chart my weekly steps: 8000, 6500, 9000\".",
+        ),
+        Tool::new_function::<AddTaskArgs>(
+            "add_task",
+            "Add a to-do to the user's task list, e.g. \"add buy milk, high priority\".",
+        ),
+        Tool::new_function::<ListTasksArgs>(
+            "list_tasks",
+            "Tell the user the to-dos in their task list that are not done yet.",
+        ),
+        Tool::new_function::<FinishTaskArgs>(
+            "finish_task",
+            "Mark a to-do in the user's task list as done, e.g. \"I bought the milk\".",
+        ),
+        Tool::new_function::<SetReminderArgs>(
+            "set_reminder",
+            "Remind the user of something at a later time, e.g. \"remind me in 20 minutes to stand up\".",
+        ),
+        Tool::new_function::<SetTimerArgs>(
+            "set_timer",
+            "Start a countdown timer, e.g. \"set a 10 minute timer for the pasta\".",
+        ),
+        Tool::new_function::<ListRemindersArgs>(
+            "list_reminders",
+            "Tell the user the reminders and timers that haven't gone off yet.",
+        ),
+        Tool::new_function::<CancelReminderArgs>(
+            "cancel_reminder",
+            "Cancel a pending reminder or timer, or all of them.",
+        ),
+        Tool::new_function::<FetchUrlArgs>(
+            "fetch_url",
+            "Read the web page at the url to summarize it or answer questions about it, e.g. \"summarize this article https://...\".",
+        ),
+        Tool::new_function::<SearchDocumentsArgs>(
+            "search_documents",
+            "Answer a question from the pdf, markdown or text files the user uploaded or the team's notes and docs, e.g. \"what does my lease say about pets\" or \"what did we decide about the deploy freeze\".",
+        ),
+        Tool::new_function::<RememberArgs>(
+            "remember",
+            "Remember a lasting fact or preference of the user for later conversations, e.g. \"remember that I prefer Rust examples\".",
+        ),
+        Tool::new_function::<ForgetArgs>(
+            "forget",
+            "Forget something the user asked to remember before, e.g. \"forget what I said about Rust\".",
+        ),
+        Tool::new_function::<RecallArgs>(
+            "recall",
+            "Tell the user what was remembered about them, e.g. \"what do you know about me\".",
+        ),
+        Tool::new_function::<EditImageArgs>(
+            "edit_image",
+            "Edit the image drawn previously, or the one the user picked, or create variations of it, e.g. \"make it darker\".",
+        ),
+        Tool::new_function::<ExplainArgs>(
+            "explain",
+            "Explain a technical topic in depth, optionally with an illustration and example code.",
+        ),
+        Tool::new_function::<TranslationModeArgs>(
+            "translation_mode",
+            "Turn on or off the voice translation mode, which translates everything the user says.",
+        ),
     ]
 }
 
+fn default_image_count() -> usize {
+    1
+}
+
+fn default_fetch_prompt() -> String {
+    "Summarize the page".to_string()
+}
+
+impl DrawImageArgs {
+    pub(crate) fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            size: ImageAspect::default(),
+            quality: DrawImageQuality::default(),
+            style: DrawImageStyle::default(),
+            count: default_image_count(),
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count.clamp(1, MAX_IMAGE_COUNT)
+    }
+}
+
+impl From<ImageAspect> for ImageSize {
+    fn from(aspect: ImageAspect) -> Self {
+        match aspect {
+            ImageAspect::Square => ImageSize::Large,
+            ImageAspect::Wide => ImageSize::LargeWide,
+            ImageAspect::Tall => ImageSize::LargeTall,
+        }
+    }
+}
+
+impl From<DrawImageQuality> for ImageQuality {
+    fn from(quality: DrawImageQuality) -> Self {
+        match quality {
+            DrawImageQuality::Standard => ImageQuality::Standard,
+            DrawImageQuality::Hd => ImageQuality::Hd,
+        }
+    }
+}
+
+impl From<DrawImageStyle> for ImageStyle {
+    fn from(style: DrawImageStyle) -> Self {
+        match style {
+            DrawImageStyle::Vivid => ImageStyle::Vivid,
+            DrawImageStyle::Natural => ImageStyle::Natural,
+        }
+    }
+}
+
 impl DrawImageResult {
-    pub(crate) fn new(url: impl Into<String>, prompt: impl Into<String>) -> Self {
+    /// Placeholder while the images are being drawn.
+    pub(crate) fn new_pending(prompt: impl Into<String>, count: usize) -> Self {
         Self {
-            url: url.into(),
+            images: vec![],
             prompt: prompt.into(),
+            count,
+            audio: None,
         }
     }
+
+    pub(crate) fn new(images: Vec<GeneratedImage>) -> Self {
+        let prompt = images.first().map(|v| v.prompt.clone()).unwrap_or_default();
+        Self {
+            count: images.len(),
+            images,
+            prompt,
+            audio: None,
+        }
+    }
+
+    pub(crate) fn with_audio(mut self, audio: impl Into<Option<AudioClip>>) -> Self {
+        self.audio = audio.into();
+        self
+    }
 }
 
 impl WriteCodeResult {
     pub(crate) fn new(content: impl Into<String>) -> Self {
         Self {
             content: content.into(),
+            files: vec![],
+            archive: None,
+            audio: None,
+        }
+    }
+
+    pub(crate) fn with_files(mut self, files: Vec<CodeFile>, archive: Option<String>) -> Self {
+        self.files = files;
+        self.archive = archive;
+        self
+    }
+
+    /// Split the content at each code block, so that the block could be rendered along with
+    /// the buttons of its file. The content is kept whole if the blocks don't match the files.
+    pub(crate) fn segments(&self) -> Vec<CodeSegment<'_>> {
+        let starts: Vec<_> = self.content.match_indices("<pre").map(|(i, _)| i).collect();
+        if self.files.is_empty() || starts.len() != self.files.len() {
+            return vec![CodeSegment {
+                file: None,
+                html: &self.content,
+            }];
+        }
+
+        let mut segments = vec![CodeSegment {
+            file: None,
+            html: &self.content[..starts[0]],
+        }];
+        for (i, file) in self.files.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.content.len());
+            segments.push(CodeSegment {
+                file: Some(file),
+                html: &self.content[starts[i]..end],
+            });
+        }
+        segments
+    }
+
+    pub(crate) fn with_audio(mut self, audio: impl Into<Option<AudioClip>>) -> Self {
+        self.audio = audio.into();
+        self
+    }
+}
+
+impl CodeLanguage {
+    /// Name of the fenced code block info string.
+    pub(crate) fn fence(&self) -> &'static str {
+        match self {
+            CodeLanguage::Python => "python",
+            CodeLanguage::Javascript => "javascript",
+            CodeLanguage::Shell => "sh",
+        }
+    }
+}
+
+impl RunCodeResult {
+    /// Placeholder while the code is running.
+    pub(crate) fn new_pending(language: CodeLanguage) -> Self {
+        Self {
+            language,
+            output: None,
+            audio: None,
+        }
+    }
+
+    pub(crate) fn new(language: CodeLanguage, output: ExecutionOutput) -> Self {
+        Self {
+            language,
+            output: Some(output),
+            audio: None,
+        }
+    }
+
+    pub(crate) fn with_audio(mut self, audio: impl Into<Option<AudioClip>>) -> Self {
+        self.audio = audio.into();
+        self
+    }
+}
+
+impl TaskPriority {
+    /// sort key, the most important first
+    pub(crate) fn rank(&self) -> u8 {
+        match self {
+            TaskPriority::High => 0,
+            TaskPriority::Medium => 1,
+            TaskPriority::Low => 2,
         }
     }
 }
+
+impl DrawChartResult {
+    /// Placeholder while the chart is being drawn.
+    pub(crate) fn new_pending(title: impl Into<String>, kind: ChartKind) -> Self {
+        Self {
+            title: title.into(),
+            kind,
+            url: None,
+            audio: None,
+        }
+    }
+
+    pub(crate) fn new(title: impl Into<String>, kind: ChartKind, url: impl Into<String>) -> Self {
+        Self {
+            url: Some(url.into()),
+            ..Self::new_pending(title, kind)
+        }
+    }
+
+    /// font awesome chart icon for the kind of the chart
+    pub(crate) fn icon(&self) -> &'static str {
+        match self.kind {
+            ChartKind::Line => "line",
+            ChartKind::Bar => "column",
+            ChartKind::Pie => "pie",
+        }
+    }
+
+    pub(crate) fn with_audio(mut self, audio: impl Into<Option<AudioClip>>) -> Self {
+        self.audio = audio.into();
+        self
+    }
+}
+
+impl ExecutionOutput {
+    pub(crate) fn succeeded(&self) -> bool {
+        self.exit_code == Some(0) && !self.timed_out
+    }
+
+    /// Short description of how the process ended.
+    pub(crate) fn status(&self) -> String {
+        match (self.timed_out, self.exit_code) {
+            (true, _) => "timed out".to_string(),
+            (false, Some(code)) => format!("exited with code {}", code),
+            (false, None) => match self.signal {
+                Some(signal) => format!("killed by {}", signal_name(signal)),
+                None => "killed".to_string(),
+            },
+        }
+    }
+}
+
+fn signal_name(signal: i32) -> String {
+    match signal {
+        libc::SIGKILL => "SIGKILL".to_string(),
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        libc::SIGBUS => "SIGBUS".to_string(),
+        libc::SIGFPE => "SIGFPE".to_string(),
+        libc::SIGXFSZ => "SIGXFSZ, the file size limit".to_string(),
+        libc::SIGTERM => "SIGTERM".to_string(),
+        libc::SIGPIPE => "SIGPIPE".to_string(),
+        _ => format!("signal {}", signal),
+    }
+}
+
+impl EditImageArgs {
+    pub(crate) fn count(&self) -> usize {
+        self.count.clamp(1, MAX_IMAGE_COUNT)
+    }
+
+    /// zero-based index of the image to edit
+    pub(crate) fn index(&self) -> usize {
+        self.index.unwrap_or(1).saturating_sub(1)
+    }
+}
+
+impl GeneratedImage {
+    pub(crate) fn new(
+        id: impl Into<String>,
+        url: impl Into<String>,
+        prompt: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            url: url.into(),
+            preview: None,
+            prompt: prompt.into(),
+            edit: None,
+            lineage: vec![],
+        }
+    }
+
+    pub(crate) fn with_preview(mut self, preview: Option<ImagePreview>) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// url of the thumbnail if available, otherwise the original image
+    pub(crate) fn thumbnail_url(&self) -> &str {
+        self.preview
+            .as_ref()
+            .map(|v| v.url.as_str())
+            .unwrap_or(&self.url)
+    }
+
+    /// Mark the image as edited from the parent with the instruction.
+    pub(crate) fn edited_from(mut self, parent: &GeneratedImage, instruction: &str) -> Self {
+        let label = parent.edit.as_deref().unwrap_or("original");
+        self.lineage = parent.lineage.clone();
+        self.lineage.push(ImageLineageStep {
+            url: parent.thumbnail_url().to_string(),
+            label: label.to_string(),
+        });
+        self.edit = Some(instruction.to_string());
+        self
+    }
+}
+
+impl ImagePreview {
+    pub(crate) fn new(url: impl Into<String>, thumb: Thumbnail) -> Self {
+        Self {
+            url: url.into(),
+            width: thumb.width,
+            height: thumb.height,
+            blurhash: thumb.blurhash,
+            placeholder: thumb.placeholder,
+        }
+    }
+}
+
+impl CodeFile {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        language: impl Into<String>,
+        url: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            language: language.into(),
+            url: url.into(),
+        }
+    }
+
+    /// file name without the directories, used as the download name
+    pub(crate) fn basename(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+}
+
+impl AudioClip {
+    pub(crate) fn new(url: impl Into<String>, format: AudioFormat) -> Self {
+        Self {
+            url: url.into(),
+            format,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        markdown::{md2html, Highlighter},
+        preferences::CodeTheme,
+    };
+
+    #[test]
+    fn test_draw_image_args_should_have_defaults() {
+        let args: DrawImageArgs = serde_json::from_str(r#"{"prompt":"a cat"}"#).unwrap();
+        assert_eq!(args.size, ImageAspect::Square);
+        assert_eq!(args.count(), 1);
+
+        let args: DrawImageArgs =
+            serde_json::from_str(r#"{"prompt":"a banner","size":"wide","quality":"hd","count":9}"#)
+                .unwrap();
+        assert_eq!(ImageSize::from(args.size), ImageSize::LargeWide);
+        assert_eq!(ImageQuality::from(args.quality), ImageQuality::Hd);
+        assert_eq!(args.count(), MAX_IMAGE_COUNT);
+    }
+
+    #[test]
+    fn test_write_code_result_should_add_buttons_to_each_block() {
+        let highlighter = Highlighter::new("");
+        let content = md2html(
+            "Two files:\n\n```rust\nfn a() {}\n```\n\nand\n\n```rust\nfn b() {}\n```\n",
+            &highlighter.themed(CodeTheme::InspiredGithub),
+        );
+        let files = vec![
+            CodeFile::new("src/a.rs", "rust", "/code/d/1/src/a.rs"),
+            CodeFile::new("src/b.rs", "rust", "/code/d/1/src/b.rs"),
+        ];
+        let ret =
+            WriteCodeResult::new(content).with_files(files, Some("/code/d/1.zip".to_string()));
+        let segments = ret.segments();
+        assert_eq!(segments.len(), 3);
+        assert!(segments[1].html.starts_with("<pre") && segments[1].html.contains("and"));
+
+        let html = ret.render().unwrap();
+        assert!(html.contains(r#"href='/code/d/1.zip'"#));
+        assert!(html.contains(r#"download='b.rs'"#));
+        assert_eq!(html.matches("<div data-code-toolbar").count(), 2);
+
+        let ret = WriteCodeResult::new("<pre>x</pre>").with_files(vec![], None);
+        assert_eq!(ret.segments().len(), 1);
+    }
+}
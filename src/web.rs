@@ -0,0 +1,422 @@
+use anyhow::{anyhow, bail, Result};
+use reqwest::{header, redirect::Policy, Url};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::lookup_host;
+
+const USER_AGENT: &str = "Mozilla/5.0 (compatible; ava-bot)";
+// the most likely containers of the readable content, in order of preference
+const CONTENT_SELECTORS: &[&str] = &["article", "main", "[role=main]", "body"];
+// boilerplate that is never part of the readable text
+const SKIPPED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "canvas", "iframe", "form", "button", "nav",
+    "header", "footer", "aside", "menu", "dialog",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) struct FetchLimits {
+    /// max bytes downloaded, the rest of the page is ignored
+    pub(crate) max_bytes: usize,
+    /// max chars of text given to the model
+    pub(crate) max_chars: usize,
+    /// for the whole download, redirects included
+    pub(crate) timeout: Duration,
+    pub(crate) max_redirects: usize,
+    /// whether loopback and private network addresses can be fetched
+    pub(crate) allow_private: bool,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 2 * 1024 * 1024,
+            max_chars: 20_000,
+            timeout: Duration::from_secs(10),
+            max_redirects: 5,
+            allow_private: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WebPage {
+    /// url after redirects
+    pub(crate) url: String,
+    pub(crate) title: Option<String>,
+    /// readable text of the page
+    pub(crate) text: String,
+    /// whether the page was cut at the size limits
+    pub(crate) truncated: bool,
+}
+
+/// Download the page and extract its readable text.
+pub(crate) async fn fetch_page(url: &str, limits: &FetchLimits) -> Result<WebPage> {
+    tokio::time::timeout(limits.timeout, fetch(url, limits))
+        .await
+        .map_err(|_| anyhow!("timed out fetching {}", url))?
+}
+
+async fn fetch(url: &str, limits: &FetchLimits) -> Result<WebPage> {
+    let mut url = Url::parse(url.trim())?;
+    let mut redirects = 0;
+    let mut res = loop {
+        let res = request(&url, limits).await?;
+        if !res.status().is_redirection() {
+            break res;
+        }
+        // redirects are followed by hand, so that every hop is checked
+        redirects += 1;
+        if redirects > limits.max_redirects {
+            bail!("too many redirects fetching {}", url);
+        }
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| anyhow!("redirect without location from {}", url))?;
+        url = url.join(location)?;
+    };
+
+    let status = res.status();
+    if !status.is_success() {
+        bail!("failed to fetch {}: {}", url, status);
+    }
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/html")
+        .to_lowercase();
+    let is_html = content_type.contains("html");
+    if !is_html && !content_type.starts_with("text/") {
+        bail!("{} is not a web page but {}", url, content_type);
+    }
+
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > limits.max_bytes {
+            body.extend_from_slice(&chunk[..limits.max_bytes - body.len()]);
+            truncated = true;
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = String::from_utf8_lossy(&body);
+
+    let (title, text) = if is_html {
+        extract_text(&body)
+    } else {
+        (None, body.trim().to_string())
+    };
+    if text.is_empty() {
+        bail!("no readable text found on {}", url);
+    }
+    let (text, cut) = match text.char_indices().nth(limits.max_chars) {
+        Some((i, _)) => (text[..i].to_string(), true),
+        None => (text, false),
+    };
+    Ok(WebPage {
+        url: url.to_string(),
+        title,
+        text,
+        truncated: truncated || cut,
+    })
+}
+
+async fn request(url: &Url, limits: &FetchLimits) -> Result<reqwest::Response> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("only http and https urls can be fetched");
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("no host in {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = resolve(host, port, limits.allow_private).await?;
+    // connect to the address that was checked, not whatever the name resolves to next
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .user_agent(USER_AGENT)
+        .resolve(host, addr)
+        // a proxy would connect to the name again, whatever it resolves to
+        .no_proxy()
+        .build()?;
+    Ok(client.get(url.clone()).send().await?)
+}
+
+async fn resolve(host: &str, port: u16, allow_private: bool) -> Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<_> = lookup_host((host, port)).await?.collect();
+    if !allow_private && addrs.iter().any(|v| !is_public(v.ip())) {
+        bail!("{} is not a public address", host);
+    }
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{} can't be resolved", host))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v) => is_public_v4(v),
+        IpAddr::V6(v) => match embedded_ipv4(v) {
+            Some(v4) => is_public_v4(v4),
+            None => {
+                let segment = v.segments()[0];
+                !(v.is_loopback()
+                    || v.is_unspecified()
+                    || v.is_multicast()
+                    // unique local fc00::/7 and link local fe80::/10
+                    || (segment & 0xfe00) == 0xfc00
+                    || (segment & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+fn is_public_v4(v: Ipv4Addr) -> bool {
+    let [a, b, ..] = v.octets();
+    !(v.is_loopback()
+        || v.is_private()
+        || v.is_link_local()
+        || v.is_documentation()
+        || v.is_multicast()
+        // "this network" 0.0.0.0/8
+        || a == 0
+        // carrier-grade nat, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // benchmarking, 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved 240.0.0.0/4, with the broadcast address
+        || a >= 240)
+}
+
+/// The ipv4 address an ipv6 one leads to: mapped ::ffff:0:0/96, nat64 64:ff9b::/96 and 6to4
+/// 2002::/16.
+fn embedded_ipv4(v: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = v.segments();
+    let from_segments = |hi: u16, lo: u16| Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo));
+    match s {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] | [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => {
+            Some(from_segments(hi, lo))
+        }
+        [0x2002, hi, lo, ..] => Some(from_segments(hi, lo)),
+        _ => None,
+    }
+}
+
+/// The title and the readable text of the html, without navigation, scripts and the like.
+pub(crate) fn extract_text(html: &str) -> (Option<String>, String) {
+    let doc = Html::parse_document(html);
+    let title = Selector::parse("title")
+        .ok()
+        .and_then(|s| doc.select(&s).next())
+        .map(|v| collapse_whitespace(&v.text().collect::<String>()))
+        .filter(|v| !v.is_empty());
+
+    let root = CONTENT_SELECTORS
+        .iter()
+        .filter_map(|v| Selector::parse(v).ok())
+        .find_map(|s| doc.select(&s).next())
+        .unwrap_or_else(|| doc.root_element());
+    let mut text = TextBuilder::default();
+    text.walk(root);
+    (title, text.finish())
+}
+
+#[derive(Default)]
+struct TextBuilder {
+    lines: Vec<String>,
+    current: String,
+}
+
+impl TextBuilder {
+    fn walk(&mut self, el: ElementRef) {
+        for child in el.children() {
+            match child.value() {
+                Node::Text(v) => self.current.push_str(v),
+                Node::Element(v) => {
+                    let name = v.name();
+                    if SKIPPED_TAGS.contains(&name) {
+                        continue;
+                    }
+                    let Some(child) = ElementRef::wrap(child) else {
+                        continue;
+                    };
+                    if is_block(name) {
+                        self.flush();
+                        if name == "li" {
+                            self.current.push_str("- ");
+                        }
+                        self.walk(child);
+                        self.flush();
+                    } else {
+                        self.walk(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let line = collapse_whitespace(&self.current);
+        if !line.is_empty() && line != "-" {
+            self.lines.push(line);
+        }
+        self.current.clear();
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        self.lines.join("\n")
+    }
+}
+
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div"
+            | "section"
+            | "article"
+            | "main"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "li"
+            | "ul"
+            | "ol"
+            | "pre"
+            | "blockquote"
+            | "table"
+            | "tr"
+            | "br"
+            | "hr"
+            | "figcaption"
+            | "dt"
+            | "dd"
+    )
+}
+
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spawn_fake;
+    use axum::{
+        http::header::{CONTENT_TYPE, LOCATION},
+        response::{IntoResponse, Redirect},
+        routing::get,
+        Router,
+    };
+
+    const ARTICLE: &str = r#"<html><head><title>Rust  1.75</title><script>var x = 1;</script></head>
+<body><nav><a href="/">Home</a></nav>
+<article><h1>Async fn in traits</h1><p>Rust 1.75 <b>stabilizes</b> async fn in traits.</p>
+<ul><li>impl Trait in return position</li></ul><aside>Subscribe!</aside></article>
+<footer>Copyright</footer></body></html>"#;
+
+    /// A local web site serving the fixtures.
+    fn fixture_server() -> String {
+        let app = Router::new()
+            .route(
+                "/article",
+                get(|| async { ([(CONTENT_TYPE, "text/html")], ARTICLE) }),
+            )
+            .route("/moved", get(|| async { Redirect::temporary("/article") }))
+            .route(
+                "/loop",
+                get(|| async { ([(LOCATION, "/loop")], axum::http::StatusCode::FOUND) }),
+            )
+            .route("/big", get(|| async { "a".repeat(100_000) }))
+            .route(
+                "/image",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], vec![0u8; 16]).into_response() }),
+            )
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "late"
+                }),
+            );
+        spawn_fake(app)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_page_should_extract_text_within_limits() {
+        let base = fixture_server();
+        let limits = FetchLimits {
+            max_bytes: 1000,
+            timeout: Duration::from_millis(500),
+            allow_private: true,
+            ..Default::default()
+        };
+
+        let page = fetch_page(&format!("{}/moved", base), &limits)
+            .await
+            .unwrap();
+        assert_eq!(page.url, format!("{}/article", base));
+        assert_eq!(page.title.as_deref(), Some("Rust 1.75"));
+        assert_eq!(
+            page.text,
+            "Async fn in traits\nRust 1.75 stabilizes async fn in traits.\n- impl Trait in return position"
+        );
+        assert!(!page.truncated);
+
+        let page = fetch_page(&format!("{}/big", base), &limits).await.unwrap();
+        assert_eq!(page.text.len(), 1000);
+        assert!(page.truncated);
+
+        for path in ["/loop", "/image", "/slow"] {
+            let ret = fetch_page(&format!("{}{}", base, path), &limits).await;
+            assert!(ret.is_err(), "{}", path);
+        }
+        let ret = fetch_page(&format!("{}/article", base), &FetchLimits::default()).await;
+        assert!(ret
+            .unwrap_err()
+            .to_string()
+            .contains("not a public address"));
+        assert!(fetch_page("file:///etc/passwd", &limits).await.is_err());
+    }
+
+    #[test]
+    fn test_is_public_should_reject_special_ranges() {
+        let public = ["93.184.216.34", "2606:2800:220:1::1", "2002:5db8:d822::1"];
+        for ip in public {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        let special = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "100.64.0.1",
+            // multicast
+            "224.0.0.1",
+            "ff02::1",
+            // this network, benchmarking and reserved
+            "0.1.2.3",
+            "198.19.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            // mapped, nat64 and 6to4 addresses of private ones
+            "::ffff:10.0.0.1",
+            "::ffff:0.0.0.0",
+            "64:ff9b::7f00:1",
+            "64:ff9b::c612:1",
+            "2002:a9fe:a9fe::1",
+            "2002:7f00:1::",
+        ];
+        for ip in special {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}